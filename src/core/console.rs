@@ -1,9 +1,28 @@
-use neptune_privacy::api::export::{KeyType, NativeCurrencyAmount, Network, ReceivingAddress};
+use neptune_privacy::{
+    api::export::{BlockHeight, KeyType, NativeCurrencyAmount, Network, ReceivingAddress},
+    state::wallet::wallet_entropy::WalletEntropy,
+};
+use num_traits::Zero;
+use rand::seq::index::sample;
 use rustyline::{DefaultEditor, error::ReadlineError};
-use std::str::FromStr;
-use tracing::{info, warn};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::{FromStr, SplitWhitespace};
+use std::sync::Arc;
+use tracing::{error, info, warn};
 
-use crate::wallet::flow::Wallet;
+use crate::Error;
+use crate::wallet::{
+    builder::{
+        payout::{PayoutReport, parse_payout_csv, payout_total},
+        transaction::TransactionPreview,
+    },
+    cache::pending::PendingStatus,
+    flow::Wallet,
+    history::{HistoryEntry, HistoryEvent},
+    manager::WalletManager,
+    utils::selection::CoinSelection,
+};
 
 #[derive(Debug)]
 enum Command {
@@ -11,6 +30,16 @@ enum Command {
     Balance,
     Address,
     Send,
+    SendMany,
+    Payout,
+    Preview,
+    Sweep,
+    Rescan,
+    ChangePassphrase,
+    History,
+    Pending,
+    Quarantine,
+    Wallet,
     Unknown(String),
 }
 
@@ -23,14 +52,266 @@ impl FromStr for Command {
             "balance" => Ok(Command::Balance),
             "address" => Ok(Command::Address),
             "send" => Ok(Command::Send),
+            "send-many" => Ok(Command::SendMany),
+            "payout" => Ok(Command::Payout),
+            "preview" => Ok(Command::Preview),
+            "sweep" => Ok(Command::Sweep),
+            "rescan" => Ok(Command::Rescan),
+            "change-passphrase" => Ok(Command::ChangePassphrase),
+            "history" => Ok(Command::History),
+            "pending" => Ok(Command::Pending),
+            "quarantine" => Ok(Command::Quarantine),
+            "wallet" => Ok(Command::Wallet),
             cmd => Ok(Command::Unknown(cmd.to_string())),
         }
     }
 }
 
-pub async fn start_console(wallet: Wallet) {
+const CONFIRMED_WORDS: usize = 3;
+const HISTORY_PAGE_SIZE: usize = 20;
+const DEFAULT_PAYOUT_OUTPUTS: usize = 20;
+
+// Generates a new mnemonic, shows it once and has the user type back a few of its words.
+pub fn create_mnemonic() -> crate::error::Result<String> {
+    let words = WalletEntropy::new_random().to_phrase();
+
+    println!("Write down your mnemonic, it will not be shown again:\n");
+    for (index, word) in words.iter().enumerate() {
+        println!("{:>2}. {}", index + 1, word);
+    }
+    print!("\nPress enter once it is written down...");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut String::new())?;
+    print!("\x1B[2J\x1B[1;1H"); // Clear the screen so the phrase doesn't linger.
+
+    let mut positions = sample(&mut rand::rng(), words.len(), CONFIRMED_WORDS).into_vec();
+    positions.sort_unstable();
+    for position in positions {
+        loop {
+            print!("Word #{}: ", position + 1);
+            io::stdout().flush()?;
+
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            match answer.trim() {
+                "" => return Err(Error::Validation("wallet creation aborted".to_string())),
+                answer if answer == words[position] => break,
+                _ => println!("That's not it, try again (leave empty to abort)."),
+            }
+        }
+    }
+
+    Ok(words.join(" "))
+}
+
+fn confirm(prompt: &str) -> bool {
+    print!("{prompt} [y/N] ");
+    if io::stdout().flush().is_err() {
+        return false;
+    }
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+// Takes the next argument as an address on `network`, warning if it is missing or invalid.
+fn parse_address(args: &mut SplitWhitespace, network: Network) -> Option<ReceivingAddress> {
+    let Some(address) = args.next() else {
+        warn!("Missing address.");
+        return None;
+    };
+    match ReceivingAddress::from_bech32m(address, network) {
+        Ok(address) => Some(address),
+        Err(e) => {
+            warn!("Invalid address: {e}.");
+            None
+        }
+    }
+}
+
+// Takes the next argument as an XNT amount, warning about `what` if it is missing or invalid.
+fn parse_amount(args: &mut SplitWhitespace, what: &str) -> Option<NativeCurrencyAmount> {
+    let Some(amount) = args.next() else {
+        warn!("Missing {what}.");
+        return None;
+    };
+    match NativeCurrencyAmount::coins_from_str(amount) {
+        Ok(amount) => Some(amount),
+        Err(_) => {
+            warn!("Invalid {what}: {amount}.");
+            None
+        }
+    }
+}
+
+fn print_preview(preview: &TransactionPreview, network: Network) {
+    println!("Inputs:");
+    for input in &preview.inputs {
+        println!(
+            "  {} XNT (leaf {})",
+            input.utxo.get_native_currency_amount(),
+            input.membership_proof.aocl_leaf_index
+        );
+    }
+    println!("Outputs:");
+    for (recipient, amount) in &preview.recipients {
+        println!(
+            "  {amount} XNT to {}",
+            recipient
+                .to_bech32m(network)
+                .unwrap_or_else(|_| "<unencodable address>".to_string())
+        );
+    }
+    println!("Change: {} XNT", preview.change);
+    println!("Fee: {} XNT", preview.fee);
+    println!(
+        "Spendable balance afterwards: {} XNT",
+        preview.balance_after
+    );
+    match &preview.check {
+        Some(Ok(())) => println!("Check without proofs: passed"),
+        Some(Err(e)) => println!("Check without proofs: failed ({e})"),
+        None => {}
+    }
+}
+
+fn format_recipients(recipients: &[(String, NativeCurrencyAmount)]) -> String {
+    recipients
+        .iter()
+        .map(|(address, amount)| format!("{amount} XNT to {address}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_history_entry(entry: &HistoryEntry) -> String {
+    let details = match &entry.event {
+        HistoryEvent::Received {
+            block_height,
+            leaf_index,
+            key_type,
+            key_index,
+            amount,
+            ..
+        } => format!(
+            "received {amount} XNT on block {block_height} (leaf {leaf_index}, {key_type:?} key {key_index})"
+        ),
+        HistoryEvent::Spent {
+            leaf_index, amount, ..
+        } => format!("spent    {amount} XNT (leaf {leaf_index})"),
+        HistoryEvent::Reverted {
+            leaf_index, amount, ..
+        } => format!("reverted {amount} XNT (leaf {leaf_index}, block orphaned)"),
+        HistoryEvent::Sent {
+            recipients,
+            fee,
+            kernel_id,
+        } => format!(
+            "sent     {} + {fee} XNT fee (kernel {kernel_id})",
+            format_recipients(recipients)
+        ),
+    };
+
+    format!("{}  {details}", entry.timestamp)
+}
+
+fn active_wallet(manager: &WalletManager) -> Option<Wallet> {
+    match manager.active() {
+        Ok(Some((_, wallet))) => Some(wallet),
+        Ok(None) => {
+            warn!("No wallet loaded, see `wallet list`.");
+            None
+        }
+        Err(e) => {
+            warn!("Failed to get the active wallet: {e}.");
+            None
+        }
+    }
+}
+
+fn wallet_command(manager: &WalletManager, mut args: SplitWhitespace) {
+    let action = args.next().unwrap_or("list").to_lowercase();
+    if action == "list" {
+        let active = manager.active().ok().flatten().map(|(name, _)| name);
+        match manager.list() {
+            Ok(wallets) => {
+                for (name, loaded) in wallets {
+                    let marker = if active.as_ref() == Some(&name) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    let state = if loaded { "loaded" } else { "unloaded" };
+                    println!("{marker} {name} ({state})");
+                }
+            }
+            Err(e) => warn!("Failed to list wallets: {e}."),
+        }
+        return;
+    }
+
+    let Some(name) = args.next() else {
+        warn!("Missing wallet name.");
+        return;
+    };
+    if args.next().is_some() {
+        warn!("Extra arguments for wallet command");
+        return;
+    }
+
+    let result = match action.as_str() {
+        "create" => {
+            if manager.exists(name) {
+                warn!("Wallet {name} already exists.");
+                return;
+            }
+            let (Ok(passphrase), Ok(repeated)) = (
+                rpassword::prompt_password("New wallet passphrase: "),
+                rpassword::prompt_password("Repeat wallet passphrase: "),
+            ) else {
+                warn!("Failed to read passphrase.");
+                return;
+            };
+            if passphrase != repeated {
+                warn!("Passphrases do not match.");
+                return;
+            }
+
+            tokio::runtime::Handle::current()
+                .block_on(manager.create(name, &passphrase, create_mnemonic))
+                .map(|_| ())
+        }
+        "load" => {
+            let Ok(passphrase) = rpassword::prompt_password("Wallet passphrase: ") else {
+                warn!("Failed to read passphrase.");
+                return;
+            };
+            manager
+                .load(name, None, &passphrase, true, None)
+                .map(|_| ())
+        }
+        "unload" => tokio::runtime::Handle::current().block_on(manager.unload(name)),
+        "use" => manager.select(name),
+        _ => {
+            warn!("Unknown wallet command: {action}");
+            return;
+        }
+    };
+
+    if let Err(e) = result {
+        warn!("Wallet command failed: {e}.");
+    }
+}
+
+pub async fn start_console(manager: Arc<WalletManager>) {
     tokio::task::spawn_blocking(move || {
-        let mut rl = DefaultEditor::new().expect("failed to init rustyline");
+        let mut rl = match DefaultEditor::new() {
+            Ok(rl) => rl,
+            Err(e) => {
+                error!("Failed to start the console: {e}.");
+                std::process::exit(1);
+            }
+        };
 
         loop {
             match rl.readline("") {
@@ -43,75 +324,458 @@ pub async fn start_console(wallet: Wallet) {
 
                     match cmd.parse::<Command>() {
                         Ok(Command::Height) => {
-                            info!("Height: {}.", wallet.scanner.height());
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            match wallet.scanner.height() {
+                                Ok(height) => info!("Height: {}.", height),
+                                Err(e) => warn!("Failed to read height: {e}."),
+                            }
                         }
                         Ok(Command::Balance) => {
-                            info!("Balance: {} XNT.", wallet.utxos.blocking_read().summary);
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            let utxos = wallet.utxos.blocking_read();
+                            match utxos.spendable() {
+                                Ok(spendable) => info!(
+                                    "Balance: {} XNT ({} XNT spendable, {} XNT reserved).",
+                                    utxos.summary, spendable, utxos.reserved
+                                ),
+                                Err(e) => warn!("Failed to read balance: {e}."),
+                            }
                         }
                         Ok(Command::Address) => {
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
                             let keys = wallet.keys.blocking_read();
-                            println!(
-                                "{}",
-                                keys.current_key(KeyType::Generation)
-                                    .to_address()
-                                    .to_bech32m(Network::Main)
-                                    .unwrap()
-                            );
+                            let address = keys.current_key(KeyType::Generation).and_then(|key| {
+                                key.to_address().to_bech32m(wallet.network).map_err(|e| {
+                                    Error::Validation(format!("cannot encode address: {e}"))
+                                })
+                            });
+                            match address {
+                                Ok(address) => println!("{address}"),
+                                Err(e) => warn!("Failed to get address: {e}."),
+                            }
                         }
                         Ok(Command::Send) => {
-                            let address_str = match parts_iter.next() {
-                                Some(s) => s,
-                                None => {
-                                    warn!("Missing address.");
-                                    continue;
-                                }
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            let Some(address) = parse_address(&mut parts_iter, wallet.network)
+                            else {
+                                continue;
+                            };
+                            let Some(amount) = parse_amount(&mut parts_iter, "amount") else {
+                                continue;
+                            };
+                            let Some(fee) = parse_amount(&mut parts_iter, "fee") else {
+                                continue;
                             };
-                            let address =
-                                match ReceivingAddress::from_bech32m(address_str, Network::Main) {
-                                    Ok(addr) => addr,
-                                    Err(e) => {
-                                        warn!("Invalid address: {}.", e);
+                            let coin_selection =
+                                match parts_iter.next().map(str::parse::<CoinSelection>) {
+                                    Some(Ok(selection)) => Some(selection),
+                                    Some(Err(e)) => {
+                                        warn!("Invalid coin selection: {e}.");
                                         continue;
                                     }
+                                    None => None,
                                 };
-                            let amount_str = match parts_iter.next() {
-                                Some(s) => s,
-                                None => {
-                                    warn!("Missing amount.");
-                                    continue;
+                            if parts_iter.next().is_some() {
+                                warn!("Extra arguments for send command");
+                                continue;
+                            }
+
+                            tokio::runtime::Handle::current().spawn(async move {
+                                if let Err(e) = wallet
+                                    .transaction_builder
+                                    .send(address, amount, fee, coin_selection)
+                                    .await
+                                {
+                                    warn!("Send failed: {e}.");
                                 }
+                            });
+                        }
+                        Ok(Command::SendMany) => {
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
                             };
-                            let amount = match NativeCurrencyAmount::coins_from_str(amount_str) {
-                                Ok(a) => a,
+                            // Arguments are address/amount pairs followed by a single fee.
+                            let args: Vec<&str> = parts_iter.collect();
+                            let Some((fee_str, pairs)) = args.split_last() else {
+                                warn!("Missing recipients and fee.");
+                                continue;
+                            };
+                            if pairs.is_empty() || pairs.len() % 2 != 0 {
+                                warn!("Expected address/amount pairs followed by a fee.");
+                                continue;
+                            }
+
+                            let mut recipients = Vec::new();
+                            for pair in pairs.chunks(2) {
+                                let address =
+                                    match ReceivingAddress::from_bech32m(pair[0], wallet.network) {
+                                        Ok(addr) => addr,
+                                        Err(e) => {
+                                            warn!("Invalid address {}: {}.", pair[0], e);
+                                            break;
+                                        }
+                                    };
+                                let amount = match NativeCurrencyAmount::coins_from_str(pair[1]) {
+                                    Ok(a) => a,
+                                    Err(_) => {
+                                        warn!("Invalid amount: {}.", pair[1]);
+                                        break;
+                                    }
+                                };
+                                recipients.push((address, amount));
+                            }
+                            if recipients.len() != pairs.len() / 2 {
+                                continue;
+                            }
+                            let fee = match NativeCurrencyAmount::coins_from_str(fee_str) {
+                                Ok(f) => f,
                                 Err(_) => {
-                                    warn!("Invalid amount: {}.", amount_str);
+                                    warn!("Invalid fee: {}.", fee_str);
+                                    continue;
+                                }
+                            };
+
+                            tokio::runtime::Handle::current().spawn(async move {
+                                if let Err(e) = wallet
+                                    .transaction_builder
+                                    .send_many(recipients, fee, None)
+                                    .await
+                                {
+                                    warn!("Send failed: {e}.");
+                                }
+                            });
+                        }
+                        Ok(Command::Payout) => {
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            let Some(path) = parts_iter.next().map(PathBuf::from) else {
+                                warn!("Missing payout file.");
+                                continue;
+                            };
+                            let Some(fee) = parse_amount(&mut parts_iter, "fee") else {
+                                continue;
+                            };
+                            let max_outputs = match parts_iter.next().map(str::parse::<usize>) {
+                                Some(Ok(limit)) if limit > 0 => limit,
+                                Some(_) => {
+                                    warn!("Invalid output limit.");
                                     continue;
                                 }
+                                None => DEFAULT_PAYOUT_OUTPUTS,
                             };
-                            let fee_str = match parts_iter.next() {
-                                Some(s) => s,
-                                None => {
-                                    warn!("Missing fee.");
+                            if parts_iter.next().is_some() {
+                                warn!("Extra arguments for payout command");
+                                continue;
+                            }
+
+                            let rows = match std::fs::read_to_string(&path)
+                                .map_err(Error::from)
+                                .and_then(|contents| parse_payout_csv(&contents, wallet.network))
+                            {
+                                Ok(rows) => rows,
+                                Err(e) => {
+                                    warn!("Invalid payout file: {e}.");
                                     continue;
                                 }
                             };
-                            let fee = match NativeCurrencyAmount::coins_from_str(fee_str) {
-                                Ok(f) => f,
+                            let report_path = path.with_extension("report.csv");
+                            if report_path.exists() {
+                                warn!(
+                                    "Payout report {} already exists, move it away first.",
+                                    report_path.display()
+                                );
+                                continue;
+                            }
+
+                            let (count, total) = (rows.len(), payout_total(&rows));
+                            let batches = match tokio::runtime::Handle::current().block_on(
+                                wallet
+                                    .transaction_builder
+                                    .plan_payout(rows, fee, max_outputs),
+                            ) {
+                                Ok(batches) => batches,
+                                Err(e) => {
+                                    warn!("Payout cannot be funded: {e}.");
+                                    continue;
+                                }
+                            };
+                            let fees = batches
+                                .iter()
+                                .fold(NativeCurrencyAmount::zero(), |total, _| total + fee);
+                            println!(
+                                "{count} payments totalling {total} XNT in {} transactions, {fees} XNT in fees.",
+                                batches.len()
+                            );
+                            if !confirm("Send payout?") {
+                                info!("Payout cancelled.");
+                                continue;
+                            }
+
+                            let report = match PayoutReport::create(&report_path) {
+                                Ok(report) => report,
+                                Err(e) => {
+                                    warn!("Failed to create payout report: {e}.");
+                                    continue;
+                                }
+                            };
+                            info!("Writing payout report to {}.", report_path.display());
+                            tokio::runtime::Handle::current().spawn(async move {
+                                if let Err(e) =
+                                    wallet.transaction_builder.payout(batches, report).await
+                                {
+                                    warn!("Failed to write payout report: {e}.");
+                                }
+                            });
+                        }
+                        Ok(Command::Preview) => {
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            let Some(address) = parse_address(&mut parts_iter, wallet.network)
+                            else {
+                                continue;
+                            };
+                            let Some(amount) = parse_amount(&mut parts_iter, "amount") else {
+                                continue;
+                            };
+                            let Some(fee) = parse_amount(&mut parts_iter, "fee") else {
+                                continue;
+                            };
+                            // Optional trailing options: a coin selection strategy and `check`.
+                            let mut coin_selection = None;
+                            let mut check = false;
+                            let mut invalid = false;
+                            for option in parts_iter {
+                                match (option, option.parse::<CoinSelection>()) {
+                                    ("check", _) => check = true,
+                                    (_, Ok(selection)) => coin_selection = Some(selection),
+                                    (_, Err(e)) => {
+                                        warn!("Invalid option: {e}.");
+                                        invalid = true;
+                                    }
+                                }
+                            }
+                            if invalid {
+                                continue;
+                            }
+
+                            let builder = wallet.transaction_builder.clone();
+                            let preview =
+                                tokio::runtime::Handle::current().block_on(builder.preview(
+                                    vec![(address, amount)],
+                                    fee,
+                                    coin_selection,
+                                    check,
+                                ));
+                            let preview = match preview {
+                                Ok(preview) => preview,
+                                Err(e) => {
+                                    warn!("Preview failed: {e}.");
+                                    continue;
+                                }
+                            };
+                            print_preview(&preview, wallet.network);
+                            if matches!(preview.check, Some(Err(_))) {
+                                warn!("Not sending a transaction that failed its check.");
+                                continue;
+                            }
+                            if !confirm("Send this transaction?") {
+                                info!("Transaction cancelled.");
+                                continue;
+                            }
+
+                            tokio::runtime::Handle::current().spawn(async move {
+                                if let Err(e) = builder.send_preview(preview).await {
+                                    warn!("Send failed: {e}.");
+                                }
+                            });
+                        }
+                        Ok(Command::Sweep) => {
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            let Some(address) = parse_address(&mut parts_iter, wallet.network)
+                            else {
+                                continue;
+                            };
+                            let Some(fee) = parse_amount(&mut parts_iter, "fee") else {
+                                continue;
+                            };
+                            // Any further arguments are the leaf indices of the UTXOs to sweep.
+                            let leaf_indices = match parts_iter
+                                .map(str::parse::<u64>)
+                                .collect::<Result<Vec<_>, _>>()
+                            {
+                                Ok(indices) if indices.is_empty() => None,
+                                Ok(indices) => Some(indices),
                                 Err(_) => {
-                                    warn!("Invalid fee: {}.", fee_str);
+                                    warn!("Invalid leaf index.");
+                                    continue;
+                                }
+                            };
+
+                            let builder = wallet.transaction_builder.clone();
+                            let preview = tokio::runtime::Handle::current()
+                                .block_on(builder.preview_sweep(address, fee, leaf_indices));
+                            let preview = match preview {
+                                Ok(preview) => preview,
+                                Err(e) => {
+                                    warn!("Sweep failed: {e}.");
+                                    continue;
+                                }
+                            };
+                            print_preview(&preview, wallet.network);
+                            if !confirm("Sweep these UTXOs?") {
+                                info!("Sweep cancelled.");
+                                continue;
+                            }
+
+                            tokio::runtime::Handle::current().spawn(async move {
+                                if let Err(e) = builder.send_preview(preview).await {
+                                    warn!("Sweep failed: {e}.");
+                                }
+                            });
+                        }
+                        Ok(Command::Rescan) => {
+                            let height = match parts_iter.next().map(str::parse::<u64>) {
+                                Some(Ok(h)) => Some(BlockHeight::from(h)),
+                                Some(Err(_)) => {
+                                    warn!("Invalid height.");
                                     continue;
                                 }
+                                None => None,
                             };
                             if parts_iter.next().is_some() {
-                                warn!("Extra arguments for send command");
+                                warn!("Extra arguments for rescan command");
                                 continue;
                             }
 
-                            let wallet = wallet.clone();
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
                             tokio::runtime::Handle::current().spawn(async move {
-                                wallet.transaction_builder.send(address, amount, fee).await;
+                                if let Err(e) = wallet.scanner.rescan(height).await {
+                                    warn!("Rescan failed: {e}.");
+                                }
                             });
                         }
+                        Ok(Command::ChangePassphrase) => {
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            let prompts = (
+                                rpassword::prompt_password("Current passphrase: "),
+                                rpassword::prompt_password("New passphrase: "),
+                                rpassword::prompt_password("Repeat new passphrase: "),
+                            );
+                            let (Ok(current), Ok(new), Ok(repeated)) = prompts else {
+                                warn!("Failed to read passphrase.");
+                                continue;
+                            };
+                            if new != repeated {
+                                warn!("Passphrases do not match.");
+                                continue;
+                            }
+
+                            match wallet
+                                .keys
+                                .blocking_read()
+                                .change_passphrase(&current, &new)
+                            {
+                                Ok(()) => info!("Passphrase changed."),
+                                Err(e) => warn!("Failed to change passphrase: {e}."),
+                            }
+                        }
+                        Ok(Command::History) => {
+                            let page = match parts_iter.next().map(str::parse::<usize>) {
+                                Some(Ok(page)) if page > 0 => page,
+                                Some(_) => {
+                                    warn!("Invalid page.");
+                                    continue;
+                                }
+                                None => 1,
+                            };
+                            if parts_iter.next().is_some() {
+                                warn!("Extra arguments for history command");
+                                continue;
+                            }
+
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            let offset = (page - 1) * HISTORY_PAGE_SIZE;
+                            match wallet
+                                .utxos
+                                .blocking_read()
+                                .history(offset, HISTORY_PAGE_SIZE)
+                            {
+                                Ok((entries, total)) => {
+                                    for entry in &entries {
+                                        println!("{}", format_history_entry(entry));
+                                    }
+                                    println!(
+                                        "Page {page} of {} ({total} entries).",
+                                        total.div_ceil(HISTORY_PAGE_SIZE).max(1)
+                                    );
+                                }
+                                Err(e) => warn!("Failed to read history: {e}."),
+                            }
+                        }
+                        Ok(Command::Pending) => {
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            match wallet.pending.blocking_read().list() {
+                                Ok(transactions) => {
+                                    for tx in transactions {
+                                        let status = match tx.status {
+                                            PendingStatus::Pending => "pending".to_string(),
+                                            PendingStatus::Confirmed { block_height } => {
+                                                format!("confirmed on block {block_height}")
+                                            }
+                                            PendingStatus::Expired => "stuck".to_string(),
+                                        };
+                                        println!(
+                                            "{}  {} + {} XNT fee, submitted at {}: {status}",
+                                            tx.kernel_id,
+                                            format_recipients(&tx.recipients),
+                                            tx.fee,
+                                            tx.submitted_height
+                                        );
+                                    }
+                                }
+                                Err(e) => warn!("Failed to read pending transactions: {e}."),
+                            }
+                        }
+                        Ok(Command::Quarantine) => {
+                            let Some(wallet) = active_wallet(&manager) else {
+                                continue;
+                            };
+                            match wallet.scanner.quarantined() {
+                                Ok(announcements) => {
+                                    for announcement in announcements {
+                                        println!(
+                                            "Block {} ({}): {} XNT announced for missing output {}",
+                                            announcement.block_height,
+                                            announcement.block_digest.to_hex(),
+                                            announcement.utxo.get_native_currency_amount(),
+                                            announcement.commitment.to_hex()
+                                        );
+                                    }
+                                }
+                                Err(e) => warn!("Failed to read quarantined announcements: {e}."),
+                            }
+                        }
+                        Ok(Command::Wallet) => wallet_command(&manager, parts_iter),
                         Ok(Command::Unknown(cmd)) => {
                             warn!("Unknown command: {}", cmd);
                         }
@@ -125,7 +789,8 @@ pub async fn start_console(wallet: Wallet) {
                     continue;
                 }
                 Err(err) => {
-                    panic!("Unexpected error: {:?}", err);
+                    error!("Console failed: {err}.");
+                    std::process::exit(1);
                 }
             }
         }
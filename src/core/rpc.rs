@@ -0,0 +1,216 @@
+use std::{
+    fmt::Debug,
+    future::Future,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use itertools::Itertools;
+use neptune_privacy::{
+    api::export::{BlockHeight, Digest},
+    application::json_rpc::core::api::rpc::RpcApi,
+    protocol::consensus::block::block_selector::BlockSelector,
+};
+use tokio::time::{sleep, timeout};
+use tracing::warn;
+use xnt_rpc_client::http::HttpClient;
+
+use crate::error::{Error, Result};
+
+#[derive(Clone, Copy, Debug)]
+pub enum RpcMethod {
+    Height,
+    GetBlockDigest,
+    GetBlockTransactionKernel,
+    GetBlockBody,
+    RestoreMembershipProof,
+    SubmitTransaction,
+}
+
+impl RpcMethod {
+    // Whether repeating the call after a failure or timeout can't have unintended side effects.
+    pub fn is_retry_safe(self) -> bool {
+        !matches!(self, RpcMethod::SubmitTransaction)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // Exponential backoff with "equal jitter": somewhere between half and all of the capped delay.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        let half = delay.as_millis() as u64 / 2;
+
+        Duration::from_millis(half + rand::random_range(0..=half))
+    }
+}
+
+struct Node {
+    url: String,
+    client: HttpClient,
+}
+
+// A pool of nodes where every call goes to the active node, failing over to the next one on errors.
+#[derive(Clone)]
+pub struct RpcClient {
+    nodes: Arc<Vec<Node>>,
+    active: Arc<AtomicUsize>,
+    policy: RetryPolicy,
+    max_lag: u64,
+    cross_check: bool,
+}
+
+impl RpcClient {
+    pub fn new(urls: Vec<String>, policy: RetryPolicy, max_lag: u64, cross_check: bool) -> Self {
+        let nodes = urls
+            .into_iter()
+            .map(|url| Node {
+                client: HttpClient::new(url.clone()),
+                url,
+            })
+            .collect();
+
+        RpcClient {
+            nodes: Arc::new(nodes),
+            active: Arc::new(AtomicUsize::new(0)),
+            policy,
+            max_lag,
+            cross_check,
+        }
+    }
+
+    // Runs `call` under the per-call timeout, retrying with backoff if `method` is retry safe.
+    pub async fn call<T, E, F, Fut>(&self, method: RpcMethod, call: F) -> Result<T>
+    where
+        F: Fn(HttpClient) -> Fut,
+        Fut: Future<Output = std::result::Result<T, E>>,
+        E: Debug,
+    {
+        let attempts = if method.is_retry_safe() {
+            self.policy.attempts.max(1)
+        } else {
+            1
+        };
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let index = self.active.load(Ordering::Relaxed);
+            let node = &self.nodes[index];
+            let error = match timeout(self.policy.timeout, call(node.client.clone())).await {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(e)) => Error::Rpc(format!("{method:?} on {} failed: {e:?}", node.url)),
+                Err(_) => Error::Rpc(format!(
+                    "{method:?} on {} timed out after {:?}",
+                    node.url, self.policy.timeout
+                )),
+            };
+
+            if attempt >= attempts {
+                return Err(error);
+            }
+
+            self.fail_over(index, (index + 1) % self.nodes.len());
+            let delay = self.policy.backoff(attempt);
+            warn!("{error} (attempt {attempt}/{attempts}), retrying in {delay:?}.");
+            sleep(delay).await;
+        }
+    }
+
+    // Returns the active node's height, first switching away from it if it is unreachable or
+    // lags more than `max_lag` blocks behind the highest node of the pool.
+    pub async fn height(&self) -> Result<BlockHeight> {
+        let heights = join_all(self.nodes.iter().map(|node| async move {
+            match timeout(self.policy.timeout, node.client.height()).await {
+                Ok(Ok(response)) => Some(response.height),
+                _ => None,
+            }
+        }))
+        .await;
+
+        let Some((best, best_height)) = heights
+            .iter()
+            .enumerate()
+            .filter_map(|(index, height)| height.map(|h| (index, h)))
+            .max_by_key(|(_, height)| *height)
+        else {
+            // Nothing answered, so fall back to retrying the active node with backoff.
+            return Ok(self
+                .call(RpcMethod::Height, |c| async move { c.height().await })
+                .await?
+                .height);
+        };
+
+        let active = self.active.load(Ordering::Relaxed);
+        match heights[active] {
+            Some(height) if height.value() + self.max_lag >= best_height.value() => Ok(height),
+            _ => {
+                self.fail_over(active, best);
+                Ok(best_height)
+            }
+        }
+    }
+
+    // When cross-checking is enabled, fails if the reachable nodes that know the block at
+    // `height` report different digests for it.
+    pub async fn cross_check(&self, height: BlockHeight) -> Result<()> {
+        if !self.cross_check || self.nodes.len() < 2 {
+            return Ok(());
+        }
+
+        let digests: Vec<(&str, Digest)> = join_all(self.nodes.iter().map(|node| async move {
+            let selector = BlockSelector::Height(height);
+            match timeout(self.policy.timeout, node.client.get_block_digest(selector)).await {
+                Ok(Ok(response)) => response.digest.map(|digest| (node.url.as_str(), digest)),
+                _ => None,
+            }
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+        if digests.iter().map(|(_, digest)| digest).all_equal() {
+            return Ok(());
+        }
+
+        let views = digests
+            .iter()
+            .map(|(url, digest)| format!("{url}: {}", digest.to_hex()))
+            .join(", ");
+        warn!("Nodes disagree on block {height}: {views}.");
+        Err(Error::Rpc(format!("nodes disagree on block {height}")))
+    }
+
+    fn fail_over(&self, from: usize, to: usize) {
+        if from == to {
+            return;
+        }
+
+        // Only the first caller noticing a failing node moves the pool along.
+        if self
+            .active
+            .compare_exchange(from, to, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            warn!(
+                "Switching RPC node from {} to {}.",
+                self.nodes[from].url, self.nodes[to].url
+            );
+        }
+    }
+}
@@ -2,34 +2,85 @@ use std::marker::PhantomData;
 use std::path::Path;
 
 use fjall::{KeyspaceCreateOptions, Readable, SingleWriterTxDatabase, SingleWriterTxKeyspace};
-use neptune_privacy::api::export::{BlockHeight, Digest, KeyType};
+use neptune_privacy::{
+    api::export::{BlockHeight, Digest, KeyType, Network},
+    protocol::consensus::transaction::transaction_kernel::TransactionKernelId,
+};
 use serde_json;
 
-use crate::wallet::cache::utxos::LockedUtxo;
+use crate::{
+    error::{Error, Result},
+    wallet::{
+        cache::{
+            pending::PendingTransaction,
+            utxos::{LockedUtxo, SpentUtxo},
+        },
+        history::HistoryEntry,
+        scanner::SuspiciousAnnouncement,
+        utils::encryption::EncryptedMnemonic,
+    },
+};
 
+pub type BlocksKeyspace = Keyspace<BlockHeight, Digest>;
+pub type HistoryKeyspace = Keyspace<u64, HistoryEntry>;
+pub type HistoryIndexKeyspace = Keyspace<HistoryKey, u64>;
 pub type KeysKeyspace = Keyspace<KeyType, u64>;
+pub type PendingKeyspace = Keyspace<TransactionKernelId, PendingTransaction>;
+pub type QuarantineKeyspace = Keyspace<BlockHeight, SuspiciousAnnouncement>;
+pub type ReservationsKeyspace = Keyspace<UtxoKey, TransactionKernelId>;
+pub type SpentKeyspace = Keyspace<UtxoKey, SpentUtxo>;
 pub type UtxosKeyspace = Keyspace<UtxoKey, LockedUtxo>;
 pub type WalletKeyspace = Keyspace<(), ()>;
 
+pub const KEYSPACE_BLOCKS: &str = "blocks";
+pub const KEYSPACE_HISTORY: &str = "history";
+pub const KEYSPACE_HISTORY_INDEX: &str = "history_index";
 pub const KEYSPACE_KEYS: &str = "keys";
+pub const KEYSPACE_PENDING: &str = "pending";
+pub const KEYSPACE_QUARANTINE: &str = "quarantine";
+pub const KEYSPACE_RESERVATIONS: &str = "reservations";
+pub const KEYSPACE_SPENT: &str = "spent";
 pub const KEYSPACE_UTXOS: &str = "utxos";
 pub const KEYSPACE_WALLET: &str = "wallet";
 
 pub struct Storage {
+    pub blocks: BlocksKeyspace,
+    pub history: HistoryKeyspace,
+    pub history_index: HistoryIndexKeyspace,
     pub keys: KeysKeyspace,
+    pub pending: PendingKeyspace,
+    pub quarantine: QuarantineKeyspace,
+    pub reservations: ReservationsKeyspace,
+    pub spent: SpentKeyspace,
     pub utxos: UtxosKeyspace,
     pub wallet: WalletKeyspace,
 }
 
 impl Storage {
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
-        let db = SingleWriterTxDatabase::builder(path).open().unwrap();
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let db = SingleWriterTxDatabase::builder(path).open()?;
 
-        Storage {
-            keys: Keyspace::new(db.clone(), KEYSPACE_KEYS),
-            utxos: Keyspace::new(db.clone(), KEYSPACE_UTXOS),
-            wallet: Keyspace::new(db, KEYSPACE_WALLET),
-        }
+        Ok(Storage {
+            blocks: Keyspace::new(db.clone(), KEYSPACE_BLOCKS)?,
+            history: Keyspace::new(db.clone(), KEYSPACE_HISTORY)?,
+            history_index: Keyspace::new(db.clone(), KEYSPACE_HISTORY_INDEX)?,
+            keys: Keyspace::new(db.clone(), KEYSPACE_KEYS)?,
+            pending: Keyspace::new(db.clone(), KEYSPACE_PENDING)?,
+            quarantine: Keyspace::new(db.clone(), KEYSPACE_QUARANTINE)?,
+            reservations: Keyspace::new(db.clone(), KEYSPACE_RESERVATIONS)?,
+            spent: Keyspace::new(db.clone(), KEYSPACE_SPENT)?,
+            utxos: Keyspace::new(db.clone(), KEYSPACE_UTXOS)?,
+            wallet: Keyspace::new(db, KEYSPACE_WALLET)?,
+        })
+    }
+}
+
+#[cfg(test)]
+impl Storage {
+    // A fresh database under the system temporary directory.
+    pub fn temporary() -> Self {
+        let path = std::env::temp_dir().join(format!("kelp-test-{}", rand::random::<u64>()));
+        Storage::new(path).expect("failed to open temporary storage")
     }
 }
 
@@ -42,49 +93,185 @@ pub struct Keyspace<K, V> {
 }
 
 impl<K, V> Keyspace<K, V> {
-    pub fn new(db: SingleWriterTxDatabase, name: &str) -> Self {
-        let handle = db.keyspace(name, KeyspaceCreateOptions::default).unwrap();
+    pub fn new(db: SingleWriterTxDatabase, name: &str) -> Result<Self> {
+        let handle = db.keyspace(name, KeyspaceCreateOptions::default)?;
 
-        Self {
+        Ok(Self {
             db,
             handle,
             key: PhantomData,
             value: PhantomData,
+        })
+    }
+}
+
+fn decode_u64(bytes: &[u8]) -> Result<u64> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| Error::Corrupted(format!("expected 8 bytes, found {}", bytes.len())))?;
+
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn decode_digest(bytes: &[u8]) -> Result<Digest> {
+    let digest_hex = String::from_utf8(bytes.to_vec())
+        .map_err(|_| Error::Corrupted("digest is not valid UTF-8".to_string()))?;
+
+    Digest::try_from_hex(&digest_hex)
+        .map_err(|_| Error::Corrupted(format!("invalid digest {digest_hex}")))
+}
+
+impl Keyspace<BlockHeight, Digest> {
+    pub fn get(&self, height: BlockHeight) -> Result<Option<Digest>> {
+        self.handle
+            .get(height.value().to_be_bytes())?
+            .map(|bytes| decode_digest(&bytes))
+            .transpose()
+    }
+
+    pub fn put(&self, height: BlockHeight, digest: Digest) -> Result<()> {
+        self.handle
+            .insert(height.value().to_be_bytes(), digest.to_hex().as_bytes())?;
+        Ok(())
+    }
+
+    // Removes every digest stored above `height`.
+    pub fn truncate(&self, height: BlockHeight) -> Result<()> {
+        let tx = self.db.read_tx();
+        let keys = tx
+            .range(&self.handle, (height.value() + 1).to_be_bytes()..)
+            .map(|guard| Ok(guard.into_inner()?.0))
+            .collect::<Result<Vec<_>>>()?;
+
+        for key in keys {
+            self.handle.remove(key)?;
+        }
+        Ok(())
+    }
+
+    // Removes every digest stored below `height`.
+    pub fn trim(&self, height: BlockHeight) -> Result<()> {
+        let tx = self.db.read_tx();
+        let keys = tx
+            .range(&self.handle, ..height.value().to_be_bytes())
+            .map(|guard| Ok(guard.into_inner()?.0))
+            .collect::<Result<Vec<_>>>()?;
+
+        for key in keys {
+            self.handle.remove(key)?;
+        }
+        Ok(())
+    }
+}
+
+// Entries are keyed by an increasing sequence number, so iteration follows recording order.
+impl Keyspace<u64, HistoryEntry> {
+    pub fn push(&self, entry: HistoryEntry) -> Result<u64> {
+        let tx = self.db.read_tx();
+        let sequence = match tx.iter(&self.handle).next_back() {
+            Some(guard) => decode_u64(&guard.into_inner()?.0)? + 1,
+            None => 0,
+        };
+
+        self.handle
+            .insert(sequence.to_be_bytes(), serde_json::to_vec(&entry)?)?;
+        Ok(sequence)
+    }
+
+    // Sequence numbers start at zero and entries are never removed, so the last one counts them.
+    pub fn len(&self) -> Result<usize> {
+        let tx = self.db.read_tx();
+        match tx.iter(&self.handle).next_back() {
+            Some(guard) => Ok(decode_u64(&guard.into_inner()?.0)? as usize + 1),
+            None => Ok(0),
         }
     }
+
+    // Returns up to `limit` entries, newest first, skipping the `offset` newest ones.
+    pub fn page(&self, offset: usize, limit: usize) -> Result<Vec<(u64, HistoryEntry)>> {
+        let tx = self.db.read_tx();
+        tx.iter(&self.handle)
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .map(|guard| {
+                let (key, value) = guard.into_inner()?;
+                Ok((decode_u64(&key)?, serde_json::from_slice(&value)?))
+            })
+            .collect()
+    }
+}
+
+// Sequence numbers of the history entries logged about each UTXO, one per kind of event.
+impl Keyspace<HistoryKey, u64> {
+    pub fn contains(&self, key: HistoryKey) -> Result<bool> {
+        Ok(self.handle.get(key)?.is_some())
+    }
+
+    pub fn put(&self, key: HistoryKey, sequence: u64) -> Result<()> {
+        self.handle.insert(key.as_ref(), sequence.to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: HistoryKey) -> Result<()> {
+        self.handle.remove(key.as_ref())?;
+        Ok(())
+    }
 }
 
 impl Keyspace<KeyType, u64> {
-    pub fn get(&self, key: KeyType) -> u64 {
+    pub fn get(&self, key: KeyType) -> Result<u64> {
         self.handle
-            .get([key as u8])
-            .unwrap()
-            .map(|bytes| u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
-            .unwrap_or(1)
+            .get([key as u8])?
+            .map(|bytes| decode_u64(&bytes))
+            .unwrap_or(Ok(1))
     }
 
-    pub fn increment(&self, key: KeyType) {
+    pub fn set(&self, key: KeyType, value: u64) -> Result<()> {
+        self.handle.insert([key as u8], value.to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn set_mnemonic(&self, mnemonic: &EncryptedMnemonic) -> Result<()> {
+        self.handle
+            .insert("encrypted_mnemonic", serde_json::to_vec(mnemonic)?)?;
+        Ok(())
+    }
+
+    pub fn get_mnemonic(&self) -> Result<Option<EncryptedMnemonic>> {
+        self.handle
+            .get("encrypted_mnemonic")?
+            .map(|bytes| Ok(serde_json::from_slice(&bytes)?))
+            .transpose()
+    }
+
+    // Older versions stored the mnemonic unencrypted; it only remains until it is migrated.
+    pub fn get_plaintext_mnemonic(&self) -> Result<Option<String>> {
         self.handle
-            .fetch_update([key as u8], |old_value| {
-                let value = old_value
-                    .map(|bytes| u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
-                    .unwrap_or(1)
-                    + 1;
-                Some(value.to_be_bytes().into())
+            .get("mnemonic")?
+            .map(|bytes| {
+                String::from_utf8(bytes.to_vec())
+                    .map_err(|_| Error::Corrupted("stored mnemonic is not valid UTF-8".to_string()))
             })
-            .unwrap();
+            .transpose()
     }
 
-    pub fn set_mnemonic(&self, mnemonic: &str) {
+    pub fn remove_plaintext_mnemonic(&self) -> Result<()> {
+        self.handle.remove("mnemonic")?;
+        Ok(())
+    }
+
+    pub fn set_birthday(&self, height: BlockHeight) -> Result<()> {
         self.handle
-            .insert("mnemonic", mnemonic.as_bytes().to_vec())
-            .unwrap();
+            .insert("birthday", height.value().to_be_bytes())?;
+        Ok(())
     }
 
-    pub fn get_mnemonic(&self) -> Option<String> {
-        self.handle.get("mnemonic").unwrap().map(|bytes| {
-            String::from_utf8(bytes.to_vec()).expect("stored mnemonic is not valid UTF-8")
-        })
+    pub fn get_birthday(&self) -> Result<BlockHeight> {
+        self.handle
+            .get("birthday")?
+            .map(|bytes| decode_u64(&bytes).map(Into::into))
+            .unwrap_or(Ok(BlockHeight::genesis()))
     }
 }
 
@@ -101,11 +288,8 @@ impl UtxoKey {
         Self(key)
     }
 
-    pub fn extract_digest(&self) -> Digest {
-        let digest_hex_bytes = &self.0[8..];
-        let digest_hex = String::from_utf8(digest_hex_bytes.to_vec()).unwrap();
-
-        Digest::try_from_hex(&digest_hex).unwrap()
+    pub fn extract_digest(&self) -> Result<Digest> {
+        decode_digest(self.0.get(8..).unwrap_or_default())
     }
 }
 
@@ -115,55 +299,225 @@ impl AsRef<[u8]> for UtxoKey {
     }
 }
 
+// A UTXO's key followed by a tag naming the kind of event logged about it.
+#[derive(Clone)]
+pub struct HistoryKey(Vec<u8>);
+
+impl HistoryKey {
+    pub fn received(leaf_index: u64, digest: Digest) -> Self {
+        Self::new(leaf_index, digest, b'r')
+    }
+
+    pub fn spent(leaf_index: u64, digest: Digest) -> Self {
+        Self::new(leaf_index, digest, b's')
+    }
+
+    fn new(leaf_index: u64, digest: Digest, kind: u8) -> Self {
+        let mut key = UtxoKey::new(leaf_index, digest).0;
+        key.push(kind);
+        Self(key)
+    }
+}
+
+impl AsRef<[u8]> for HistoryKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl Keyspace<UtxoKey, LockedUtxo> {
-    pub fn get(&self, key: UtxoKey) -> Option<LockedUtxo> {
+    pub fn get(&self, key: UtxoKey) -> Result<Option<LockedUtxo>> {
+        self.handle
+            .get(key)?
+            .map(|bytes| Ok(serde_json::from_slice(&bytes)?))
+            .transpose()
+    }
+
+    pub fn put(&self, key: UtxoKey, utxo: LockedUtxo) -> Result<bool> {
+        let value = serde_json::to_vec(&utxo)?;
+        let previous = self
+            .handle
+            .fetch_update(key.as_ref(), |_| Some(value.clone().into()))?;
+
+        Ok(previous.is_none())
+    }
+
+    pub fn remove(&self, key: UtxoKey) -> Result<()> {
+        self.handle.remove(key.as_ref())?;
+        Ok(())
+    }
+
+    // Removes every UTXO whose AOCL leaf index is at least `leaf_index`.
+    pub fn remove_from(&self, leaf_index: u64) -> Result<Vec<LockedUtxo>> {
+        let tx = self.db.read_tx();
+        let removed = tx
+            .range(&self.handle, leaf_index.to_be_bytes()..)
+            .map(|guard| {
+                let (key, value) = guard.into_inner()?;
+                Ok((UtxoKey(key.to_vec()), serde_json::from_slice(&value)?))
+            })
+            .collect::<Result<Vec<(UtxoKey, LockedUtxo)>>>()?;
+
+        removed
+            .into_iter()
+            .map(|(key, utxo)| {
+                self.remove(key)?;
+                Ok(utxo)
+            })
+            .collect()
+    }
+
+    pub fn iter(&self) -> Result<impl Iterator<Item = (UtxoKey, LockedUtxo)> + '_> {
+        let tx = self.db.read_tx();
+        let utxos = tx
+            .iter(&self.handle)
+            .map(|guard| {
+                let (key, value) = guard.into_inner()?;
+                Ok((UtxoKey(key.to_vec()), serde_json::from_slice(&value)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(utxos.into_iter())
+    }
+}
+
+// UTXOs pruned as spent, kept while the block spending them could still be orphaned.
+impl Keyspace<UtxoKey, SpentUtxo> {
+    pub fn put(&self, key: UtxoKey, spent: &SpentUtxo) -> Result<()> {
+        self.handle
+            .insert(key.as_ref(), serde_json::to_vec(spent)?)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: UtxoKey) -> Result<()> {
+        self.handle.remove(key.as_ref())?;
+        Ok(())
+    }
+
+    pub fn iter(&self) -> Result<Vec<(UtxoKey, SpentUtxo)>> {
+        let tx = self.db.read_tx();
+        tx.iter(&self.handle)
+            .map(|guard| {
+                let (key, value) = guard.into_inner()?;
+                Ok((UtxoKey(key.to_vec()), serde_json::from_slice(&value)?))
+            })
+            .collect()
+    }
+}
+
+impl Keyspace<UtxoKey, TransactionKernelId> {
+    pub fn get(&self, key: UtxoKey) -> Result<Option<TransactionKernelId>> {
         self.handle
-            .get(key)
-            .unwrap()
-            .map(|bytes| serde_json::from_slice(&bytes).expect("invalid utxo json"))
+            .get(key)?
+            .map(|bytes| Ok(serde_json::from_slice(&bytes)?))
+            .transpose()
     }
 
-    pub fn put(&self, key: UtxoKey, utxo: LockedUtxo) -> bool {
+    pub fn put(&self, key: UtxoKey, kernel_id: TransactionKernelId) -> Result<()> {
         self.handle
-            .fetch_update(key.as_ref(), |_| {
-                Some(
-                    serde_json::to_vec(&utxo)
-                        .expect("utxo serialization failed")
-                        .into(),
-                )
+            .insert(key.as_ref(), serde_json::to_vec(&kernel_id)?)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: UtxoKey) -> Result<()> {
+        self.handle.remove(key.as_ref())?;
+        Ok(())
+    }
+
+    pub fn iter(&self) -> Result<Vec<(UtxoKey, TransactionKernelId)>> {
+        let tx = self.db.read_tx();
+        tx.iter(&self.handle)
+            .map(|guard| {
+                let (key, value) = guard.into_inner()?;
+                Ok((UtxoKey(key.to_vec()), serde_json::from_slice(&value)?))
             })
-            .unwrap()
-            .is_none()
+            .collect()
     }
+}
 
-    pub fn remove(&self, key: UtxoKey) {
-        self.handle.remove(key.as_ref()).unwrap();
+impl Keyspace<TransactionKernelId, PendingTransaction> {
+    pub fn put(&self, transaction: &PendingTransaction) -> Result<()> {
+        self.handle.insert(
+            transaction.kernel_id.to_string().as_bytes(),
+            serde_json::to_vec(transaction)?,
+        )?;
+        Ok(())
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (UtxoKey, LockedUtxo)> + '_ {
+    pub fn iter(&self) -> Result<Vec<PendingTransaction>> {
         let tx = self.db.read_tx();
-        tx.iter(&self.handle).map(|guard| {
-            let (key, value) = guard.into_inner().unwrap();
-            (
-                UtxoKey(key.to_vec()),
-                serde_json::from_slice(&value).expect("invalid utxo json"),
-            )
-        })
+        tx.iter(&self.handle)
+            .map(|guard| Ok(serde_json::from_slice(&guard.into_inner()?.1)?))
+            .collect()
     }
 }
 
+impl Keyspace<BlockHeight, SuspiciousAnnouncement> {
+    pub fn put(&self, announcement: SuspiciousAnnouncement) -> Result<()> {
+        self.handle.insert(
+            quarantine_key(announcement.block_height, announcement.commitment),
+            serde_json::to_vec(&announcement)?,
+        )?;
+        Ok(())
+    }
+
+    // Every quarantined announcement, lowest block first.
+    pub fn iter(&self) -> Result<Vec<SuspiciousAnnouncement>> {
+        let tx = self.db.read_tx();
+        tx.iter(&self.handle)
+            .map(|guard| Ok(serde_json::from_slice(&guard.into_inner()?.1)?))
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub fn get(
+        &self,
+        height: BlockHeight,
+        commitment: Digest,
+    ) -> Result<Option<SuspiciousAnnouncement>> {
+        self.handle
+            .get(quarantine_key(height, commitment))?
+            .map(|bytes| Ok(serde_json::from_slice(&bytes)?))
+            .transpose()
+    }
+}
+
+fn quarantine_key(height: BlockHeight, commitment: Digest) -> Vec<u8> {
+    let mut key = Vec::new();
+    key.extend_from_slice(&height.value().to_be_bytes());
+    key.extend_from_slice(commitment.to_hex().as_bytes());
+    key
+}
+
 impl Keyspace<(), ()> {
-    pub fn set_height(&self, height: BlockHeight) {
+    pub fn set_height(&self, height: BlockHeight) -> Result<()> {
+        self.handle.insert("height", height.value().to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn get_height(&self) -> Result<BlockHeight> {
         self.handle
-            .insert("height", height.value().to_be_bytes())
-            .unwrap();
+            .get("height")?
+            .map(|bytes| decode_u64(&bytes).map(Into::into))
+            .unwrap_or(Ok(BlockHeight::genesis()))
     }
 
-    pub fn get_height(&self) -> BlockHeight {
+    pub fn set_network(&self, network: Network) -> Result<()> {
         self.handle
-            .get("height")
-            .unwrap()
-            .map(|bytes| u64::from_be_bytes(bytes.to_vec().try_into().unwrap()).into())
-            .unwrap_or(BlockHeight::genesis())
+            .insert("network", network.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    // Wallets created before networks were recorded have none stored.
+    pub fn get_network(&self) -> Result<Option<Network>> {
+        self.handle
+            .get("network")?
+            .map(|bytes| {
+                let name = String::from_utf8(bytes.to_vec())
+                    .map_err(|_| Error::Corrupted("network is not valid UTF-8".to_string()))?;
+                name.parse()
+                    .map_err(|_| Error::Corrupted(format!("unknown network {name}")))
+            })
+            .transpose()
     }
 }
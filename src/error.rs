@@ -0,0 +1,44 @@
+use std::fmt::Debug;
+
+use neptune_privacy::api::export::NativeCurrencyAmount;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("storage error: {0}")]
+    Storage(#[from] fjall::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("corrupted storage: {0}")]
+    Corrupted(String),
+    #[error("RPC error: {0}")]
+    Rpc(String),
+    #[error(
+        "insufficient funds: {available} XNT available, {requested} XNT requested \
+         ({reserved} XNT reserved by pending transactions, {timelocked} XNT timelocked)"
+    )]
+    InsufficientFunds {
+        available: NativeCurrencyAmount,
+        requested: NativeCurrencyAmount,
+        reserved: NativeCurrencyAmount,
+        timelocked: NativeCurrencyAmount,
+    },
+    #[error("proving failed: {0}")]
+    Proving(String),
+    #[error("validation failed: {0}")]
+    Validation(String),
+}
+
+impl Error {
+    pub fn proving(err: impl Debug) -> Self {
+        Error::Proving(format!("{err:?}"))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Corrupted(err.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
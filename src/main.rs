@@ -1,25 +1,89 @@
-use std::panic;
+use std::{
+    env, fs, future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use directories::ProjectDirs;
+use neptune_privacy::api::export::Network;
+use tracing::info;
 use tracing_subscriber::EnvFilter;
-use xnt_rpc_client::http::HttpClient;
 
-use crate::wallet::flow::Wallet;
+use crate::{
+    core::rpc::{RetryPolicy, RpcClient},
+    wallet::{flow::WalletConfig, manager::WalletManager, utils::selection::CoinSelection},
+};
 
 pub mod core;
+pub mod error;
 pub mod wallet;
 
+pub use error::Error;
+
+const DEFAULT_WALLET: &str = "default";
+// Where versions without named wallets kept their only wallet.
+const LEGACY_WALLET_DIR: &str = "./wallet";
+
 #[derive(Parser)]
 #[command(name = "kelp")]
 #[command(about = "A Neptune daemon wallet")]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Cli>,
+    /// Directory holding one database per named wallet (defaults to the XDG data directory)
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// Name of the wallet to load on startup
+    #[arg(long, default_value = DEFAULT_WALLET)]
+    wallet: String,
+    /// Network to operate on; a wallet is bound to the network it was created on
+    #[arg(long, default_value_t = Network::Main)]
+    network: Network,
     /// Mnemonic to import
     #[arg(long)]
     mnemonic: Option<String>,
-    /// RPC URL to use (JSON/HTTP)
+    /// Height the imported mnemonic was created at; earlier blocks are never scanned
+    #[arg(long, requires = "mnemonic")]
+    birthday_height: Option<u64>,
+    /// File holding the wallet passphrase (otherwise KELP_PASSPHRASE or a prompt is used)
+    #[arg(long)]
+    passphrase_file: Option<PathBuf>,
+    /// RPC URL to use (JSON/HTTP); repeat to fail over between several nodes
+    #[arg(long, required = true)]
+    rpc_url: Vec<String>,
+    /// Blocks the active node may lag behind the highest node before failing over
+    #[arg(long, default_value_t = 2)]
+    max_lag: u64,
+    /// Refuse to scan when nodes report different digests for the tip
     #[arg(long)]
-    rpc_url: String,
+    cross_check: bool,
+    /// Seconds to wait for a single RPC call before giving up on it
+    #[arg(long, default_value_t = 30)]
+    rpc_timeout: u64,
+    /// Attempts for RPC calls that are safe to retry
+    #[arg(long, default_value_t = 5)]
+    rpc_attempts: u32,
+    /// Number of blocks to fetch concurrently while scanning
+    #[arg(long, default_value_t = 16)]
+    scan_window: usize,
+    /// Number of not-yet-used keys of each type to watch for payments
+    #[arg(long, default_value_t = 20)]
+    lookahead: u64,
+    /// Blocks a submitted transaction may stay unconfirmed before it is flagged as stuck
+    #[arg(long, default_value_t = 50)]
+    expire_after: u64,
+    /// Coin selection strategy used when a send doesn't name one
+    #[arg(long, value_enum, default_value_t = CoinSelection::FirstFit)]
+    coin_selection: CoinSelection,
+}
+
+#[derive(Subcommand)]
+enum Cli {
+    /// Create a new wallet from a freshly generated mnemonic
+    Create,
 }
 
 #[tokio::main]
@@ -28,17 +92,106 @@ async fn main() -> Result<()> {
     let filter = EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("kelp=info"));
     tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    let default_hook = panic::take_hook();
-    panic::set_hook(Box::new(move |panic_info| {
-        default_hook(panic_info);
-        std::process::exit(1);
-    }));
+    let creating = matches!(args.command, Some(Cli::Create));
+    if creating && args.mnemonic.is_some() {
+        anyhow::bail!("--mnemonic cannot be used when creating a wallet");
+    }
+    let (passphrase, typed) = read_passphrase(&args, creating)?;
+    let data_dir = match args.data_dir {
+        Some(dir) => dir,
+        None => ProjectDirs::from("", "", "kelp")
+            .ok_or_else(|| anyhow::anyhow!("no home directory; pass --data-dir"))?
+            .data_dir()
+            .to_path_buf(),
+    };
+    migrate_legacy_wallet(&data_dir)?;
+
+    let client = RpcClient::new(
+        args.rpc_url,
+        RetryPolicy {
+            timeout: Duration::from_secs(args.rpc_timeout),
+            attempts: args.rpc_attempts,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        },
+        args.max_lag,
+        args.cross_check,
+    );
+    let manager = Arc::new(WalletManager::new(
+        client,
+        data_dir,
+        WalletConfig {
+            network: args.network,
+            scan_window: args.scan_window,
+            lookahead: args.lookahead,
+            expire_after: args.expire_after,
+            coin_selection: args.coin_selection,
+        },
+    ));
+    if creating {
+        manager
+            .create(&args.wallet, &passphrase, core::console::create_mnemonic)
+            .await?;
+    } else {
+        manager.load(
+            &args.wallet,
+            args.mnemonic,
+            &passphrase,
+            typed,
+            args.birthday_height.map(Into::into),
+        )?;
+    }
 
-    let client = HttpClient::new(args.rpc_url);
-    let wallet = Wallet::new(client, args.mnemonic);
+    core::console::start_console(manager).await;
+
+    // Wallets scan in their own tasks; the console exits the process.
+    future::pending::<()>().await;
+    Ok(())
+}
+
+// Returns the passphrase along with whether it was typed at the prompt, as only a typed one
+// can be asked for again.
+fn read_passphrase(args: &Args, confirm: bool) -> Result<(String, bool)> {
+    if let Some(path) = &args.passphrase_file {
+        let passphrase = fs::read_to_string(path)?
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        return Ok((passphrase, false));
+    }
+    if let Ok(passphrase) = env::var("KELP_PASSPHRASE") {
+        return Ok((passphrase, false));
+    }
+
+    let passphrase = rpassword::prompt_password("Wallet passphrase: ")?;
+    if confirm && rpassword::prompt_password("Repeat wallet passphrase: ")? != passphrase {
+        anyhow::bail!("passphrases do not match");
+    }
+
+    Ok((passphrase, true))
+}
 
-    core::console::start_console(wallet.clone()).await;
+// Moves a wallet left in ./wallet by older versions into the data directory as the default
+// wallet, unless the data directory is the working directory and it is a named wallet already.
+fn migrate_legacy_wallet(data_dir: &Path) -> Result<()> {
+    let legacy = Path::new(LEGACY_WALLET_DIR);
+    let target = data_dir.join(DEFAULT_WALLET);
+    if !legacy.is_dir() || target.exists() {
+        return Ok(());
+    }
+    if fs::canonicalize(data_dir).ok() == fs::canonicalize(".").ok() {
+        return Ok(());
+    }
 
-    wallet.main_loop().await;
+    fs::create_dir_all(data_dir)?;
+    fs::rename(legacy, &target).with_context(|| {
+        format!(
+            "failed to move {LEGACY_WALLET_DIR} to {}; move it there by hand",
+            target.display()
+        )
+    })?;
+    info!(
+        "Moved the wallet in {LEGACY_WALLET_DIR} to {} as wallet {DEFAULT_WALLET}.",
+        target.display()
+    );
     Ok(())
 }
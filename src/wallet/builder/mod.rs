@@ -1 +1,2 @@
+pub mod payout;
 pub mod transaction;
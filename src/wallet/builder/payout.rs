@@ -0,0 +1,285 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use neptune_privacy::{
+    api::export::{NativeCurrencyAmount, Network, ReceivingAddress},
+    protocol::consensus::transaction::transaction_kernel::TransactionKernelId,
+};
+use num_traits::Zero;
+use tracing::{info, warn};
+
+use crate::{
+    error::{Error, Result},
+    wallet::builder::transaction::{TransactionBuilder, TransactionPreview},
+};
+
+// How many invalid rows are listed before the rest are only counted.
+const REPORTED_ERRORS: usize = 10;
+
+#[derive(Clone)]
+pub struct PayoutRow {
+    // 1-based line number in the source file.
+    pub line: usize,
+    pub address: String,
+    pub recipient: ReceivingAddress,
+    pub amount: NativeCurrencyAmount,
+}
+
+// One transaction of a payout, with the inputs selected for it when the payout was planned.
+pub struct PayoutBatch {
+    pub rows: Vec<PayoutRow>,
+    pub preview: TransactionPreview,
+}
+
+// Parses `address,amount` rows, validating every one of them before returning. An optional
+// header row starting with "address" and empty lines are skipped.
+pub fn parse_payout_csv(contents: &str, network: Network) -> Result<Vec<PayoutRow>> {
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || (index == 0 && line.to_lowercase().starts_with("address")) {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [address, amount] = fields.as_slice() else {
+            errors.push(format!("line {line_number}: expected address,amount"));
+            continue;
+        };
+        let recipient = match ReceivingAddress::from_bech32m(address, network) {
+            Ok(recipient) => recipient,
+            Err(e) => {
+                errors.push(format!("line {line_number}: invalid address: {e}"));
+                continue;
+            }
+        };
+        let amount = match NativeCurrencyAmount::coins_from_str(amount) {
+            Ok(amount) if amount > NativeCurrencyAmount::zero() => amount,
+            _ => {
+                errors.push(format!("line {line_number}: invalid amount {amount}"));
+                continue;
+            }
+        };
+
+        rows.push(PayoutRow {
+            line: line_number,
+            address: address.to_string(),
+            recipient,
+            amount,
+        });
+    }
+
+    if !errors.is_empty() {
+        let mut message = errors
+            .iter()
+            .take(REPORTED_ERRORS)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("; ");
+        if errors.len() > REPORTED_ERRORS {
+            message += &format!(" and {} more", errors.len() - REPORTED_ERRORS);
+        }
+        return Err(Error::Validation(message));
+    }
+    if rows.is_empty() {
+        return Err(Error::Validation("payout file has no rows".to_string()));
+    }
+
+    Ok(rows)
+}
+
+pub fn payout_total(rows: &[PayoutRow]) -> NativeCurrencyAmount {
+    rows.iter()
+        .fold(NativeCurrencyAmount::zero(), |total, row| {
+            total + row.amount
+        })
+}
+
+// Writes one `line,address,amount,result` row per payout, where result is the kernel id of
+// the transaction that paid it or the error that stopped it. Rows are written and synced as
+// each batch is sent, so the report stays accurate if the wallet stops halfway.
+pub struct PayoutReport {
+    file: File,
+}
+
+impl PayoutReport {
+    // Refuses to overwrite the report of an earlier payout.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => {
+                    Error::Validation(format!("payout report {} already exists", path.display()))
+                }
+                _ => Error::from(e),
+            })?;
+
+        file.write_all(b"line,address,amount,result\n")?;
+        file.sync_data()?;
+        Ok(PayoutReport { file })
+    }
+
+    pub fn append(
+        &mut self,
+        rows: &[PayoutRow],
+        outcome: &std::result::Result<TransactionKernelId, String>,
+    ) -> Result<()> {
+        let outcome = match outcome {
+            Ok(kernel_id) => kernel_id.to_string(),
+            Err(e) => format!("failed: {}", e.replace(',', ";")),
+        };
+        for row in rows {
+            writeln!(
+                self.file,
+                "{},{},{},{outcome}",
+                row.line, row.address, row.amount
+            )?;
+        }
+
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+impl TransactionBuilder {
+    // Splits `rows` into batches of at most `max_outputs` recipients, each its own transaction
+    // paying `fee`, and selects the inputs of every batch up front. A batch can't spend the
+    // change of an earlier one before it confirms, so each gets inputs of its own, and a payout
+    // the spendable UTXOs can't fund fails here before anything is sent.
+    pub async fn plan_payout(
+        &self,
+        rows: Vec<PayoutRow>,
+        fee: NativeCurrencyAmount,
+        max_outputs: usize,
+    ) -> Result<Vec<PayoutBatch>> {
+        let mut utxos = self.utxos.write().await;
+        utxos.sync_proofs().await?;
+
+        let mut batches: Vec<PayoutBatch> = Vec::new();
+        for rows in rows.chunks(max_outputs.max(1)) {
+            let planned: Vec<u64> = batches
+                .iter()
+                .flat_map(|batch| &batch.preview.inputs)
+                .map(|input| input.membership_proof.aocl_leaf_index)
+                .collect();
+            let recipients = rows
+                .iter()
+                .map(|row| (row.recipient.clone(), row.amount))
+                .collect();
+
+            let preview = self.select(&utxos, recipients, fee, None, &planned)?;
+            batches.push(PayoutBatch {
+                rows: rows.to_vec(),
+                preview,
+            });
+        }
+
+        Ok(batches)
+    }
+
+    // Sends every batch of a planned payout, appending its rows to `report` once it is out. A
+    // failed batch is recorded and the remaining batches are still attempted.
+    pub async fn payout(&self, batches: Vec<PayoutBatch>, mut report: PayoutReport) -> Result<()> {
+        let batch_count = batches.len();
+
+        for (index, batch) in batches.into_iter().enumerate() {
+            info!("Sending payout batch {}/{batch_count}...", index + 1);
+            let outcome = self
+                .send_preview(batch.preview)
+                .await
+                .map_err(|e| e.to_string());
+            if let Err(e) = &outcome {
+                warn!("Payout batch {}/{batch_count} failed: {e}.", index + 1);
+            }
+
+            report.append(&batch.rows, &outcome)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use neptune_privacy::{api::export::SpendingKey, state::wallet::wallet_entropy::WalletEntropy};
+
+    use super::*;
+
+    fn address() -> String {
+        SpendingKey::from(WalletEntropy::new_random().nth_generation_spending_key(0))
+            .to_address()
+            .to_bech32m(Network::Main)
+            .unwrap()
+    }
+
+    #[test]
+    fn rows_are_parsed_past_header_and_blank_lines() {
+        let (first, second) = (address(), address());
+        let contents = format!("Address,Amount\n{first},1.5\n\n {second} , 2 \n");
+
+        let rows = parse_payout_csv(&contents, Network::Main).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            (rows[0].line, rows[0].address.as_str()),
+            (2, first.as_str())
+        );
+        assert_eq!(
+            (rows[1].line, rows[1].address.as_str()),
+            (4, second.as_str())
+        );
+        assert_eq!(
+            payout_total(&rows),
+            NativeCurrencyAmount::coins_from_str("3.5").unwrap()
+        );
+    }
+
+    #[test]
+    fn every_invalid_row_is_reported() {
+        let address = address();
+        let contents = format!("nope,1\n{address},0\n{address}\n{address},1,2\n{address},1\n");
+
+        let Err(Error::Validation(message)) = parse_payout_csv(&contents, Network::Main) else {
+            panic!("invalid rows were accepted");
+        };
+        for line in 1..=4 {
+            assert!(message.contains(&format!("line {line}:")), "{message}");
+        }
+        assert!(!message.contains("line 5:"), "{message}");
+    }
+
+    #[test]
+    fn empty_file_is_rejected() {
+        assert!(parse_payout_csv("address,amount\n\n", Network::Main).is_err());
+    }
+
+    #[test]
+    fn report_is_appended_and_never_overwritten() {
+        let path = std::env::temp_dir().join(format!("kelp-report-{}.csv", rand::random::<u64>()));
+        let rows = parse_payout_csv(&format!("{},1.5\n", address()), Network::Main).unwrap();
+
+        let mut report = PayoutReport::create(&path).unwrap();
+        report
+            .append(&rows, &Err("node unreachable, retry".to_string()))
+            .unwrap();
+        assert!(PayoutReport::create(&path).is_err());
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            contents,
+            format!(
+                "line,address,amount,result\n1,{},{},failed: node unreachable; retry\n",
+                rows[0].address, rows[0].amount
+            )
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -4,13 +4,15 @@ use neptune_privacy::application::json_rpc::core::api::rpc::RpcApi;
 use neptune_privacy::application::json_rpc::core::model::wallet::transaction::{
     RpcTransaction, RpcTransactionProof,
 };
-use neptune_privacy::protocol::consensus::transaction::transaction_kernel::TransactionKernelField;
+use neptune_privacy::protocol::consensus::transaction::transaction_kernel::{
+    TransactionKernel, TransactionKernelField, TransactionKernelId,
+};
 use neptune_privacy::protocol::consensus::transaction::validity::collect_type_scripts::CollectTypeScripts;
 use neptune_privacy::protocol::consensus::transaction::validity::kernel_to_outputs::KernelToOutputs;
 use neptune_privacy::protocol::proof_abstractions::tasm::program::ConsensusProgram;
 use neptune_privacy::{
     api::export::{
-        Digest, KeyType, NativeCurrencyAmount, Network, ReceivingAddress, Timestamp, Tip5,
+        BlockHeight, KeyType, NativeCurrencyAmount, Network, ReceivingAddress, Timestamp, Tip5,
         TransactionDetails,
     },
     prelude::triton_vm::{self, stark::Stark, vm::PublicInput},
@@ -26,25 +28,62 @@ use neptune_privacy::{
     },
     state::wallet::{transaction_output::TxOutput, unlocked_utxo::UnlockedUtxo},
 };
+use num_traits::Zero;
+use tokio::sync::RwLockWriteGuard;
+use tracing::{info, warn};
 
-use tracing::info;
-use xnt_rpc_client::http::HttpClient;
+use crate::{
+    core::rpc::{RpcClient, RpcMethod},
+    error::{Error, Result},
+    wallet::{
+        cache::{
+            keys::{KeysCache, transaction_nonce},
+            pending::{PendingCache, PendingStatus, PendingTransaction},
+            utxos::{LockedUtxo, Utxos, UtxosCache},
+        },
+        utils::selection::CoinSelection,
+    },
+};
 
-use crate::wallet::cache::{keys::KeysCache, utxos::UtxosCache};
+// Inputs and outputs chosen for a transaction, before any change key is used up.
+#[derive(Clone)]
+pub struct TransactionPreview {
+    pub inputs: Vec<LockedUtxo>,
+    pub recipients: Vec<(ReceivingAddress, NativeCurrencyAmount)>,
+    pub fee: NativeCurrencyAmount,
+    pub change: NativeCurrencyAmount,
+    // Spendable balance left once the transaction is sent.
+    pub balance_after: NativeCurrencyAmount,
+    // Outcome of checking the transaction without proofs, when requested.
+    pub check: Option<std::result::Result<(), String>>,
+}
 
 #[derive(Clone)]
 pub struct TransactionBuilder {
-    client: HttpClient,
+    client: RpcClient,
+    network: Network,
+    coin_selection: CoinSelection,
     pub keys: KeysCache,
     pub utxos: UtxosCache,
+    pub pending: PendingCache,
 }
 
 impl TransactionBuilder {
-    pub fn new(client: HttpClient, keys: KeysCache, utxos: UtxosCache) -> Self {
+    pub fn new(
+        client: RpcClient,
+        network: Network,
+        coin_selection: CoinSelection,
+        keys: KeysCache,
+        utxos: UtxosCache,
+        pending: PendingCache,
+    ) -> Self {
         TransactionBuilder {
-            client: client.clone(),
+            client,
+            network,
+            coin_selection,
             keys,
             utxos,
+            pending,
         }
     }
 
@@ -53,19 +92,148 @@ impl TransactionBuilder {
         recipient: ReceivingAddress,
         amount: NativeCurrencyAmount,
         fee: NativeCurrencyAmount,
-    ) {
+        coin_selection: Option<CoinSelection>,
+    ) -> Result<TransactionKernelId> {
+        self.send_many(vec![(recipient, amount)], fee, coin_selection)
+            .await
+    }
+
+    // Pays every recipient from a single transaction with one change output, proven once.
+    pub async fn send_many(
+        &self,
+        recipients: Vec<(ReceivingAddress, NativeCurrencyAmount)>,
+        fee: NativeCurrencyAmount,
+        coin_selection: Option<CoinSelection>,
+    ) -> Result<TransactionKernelId> {
         let mut utxos = self.utxos.write().await;
-        utxos.sync_proofs().await;
+        utxos.sync_proofs().await?;
+
+        let preview = self.select(&utxos, recipients, fee, coin_selection, &[])?;
+        self.submit(utxos, preview).await
+    }
+
+    // Selects every spendable UTXO, or only those on `leaf_indices`, to be sent to `recipient`
+    // minus `fee` without a change output. The result is reviewed and then passed to
+    // `send_preview`.
+    pub async fn preview_sweep(
+        &self,
+        recipient: ReceivingAddress,
+        fee: NativeCurrencyAmount,
+        leaf_indices: Option<Vec<u64>>,
+    ) -> Result<TransactionPreview> {
+        let mut utxos = self.utxos.write().await;
+        utxos.sync_proofs().await?;
+
+        Self::select_sweep(&utxos, recipient, fee, leaf_indices.as_deref())
+    }
+
+    // Runs coin selection and builds the transaction without proving or submitting it, and
+    // without using up a change key. With `check` it is also validated without proofs.
+    pub async fn preview(
+        &self,
+        recipients: Vec<(ReceivingAddress, NativeCurrencyAmount)>,
+        fee: NativeCurrencyAmount,
+        coin_selection: Option<CoinSelection>,
+        check: bool,
+    ) -> Result<TransactionPreview> {
+        let mut utxos = self.utxos.write().await;
+        utxos.sync_proofs().await?;
+
+        let mut preview = self.select(&utxos, recipients, fee, coin_selection, &[])?;
+        if check {
+            let transaction = self.build(&utxos, &preview, false).await?;
+            preview.check = Some(
+                transaction
+                    .primitive_witness()
+                    .validate()
+                    .await
+                    .map_err(|e| format!("{e:?}")),
+            );
+        }
+        Ok(preview)
+    }
+
+    // Sends a previewed transaction with exactly the inputs it selected.
+    pub async fn send_preview(&self, preview: TransactionPreview) -> Result<TransactionKernelId> {
+        let mut utxos = self.utxos.write().await;
+        utxos.sync_proofs().await?;
+
+        // Proofs moved on since the preview, so the inputs are picked up again from the cache.
+        let inputs = utxos.refresh(&preview.inputs)?;
+        self.submit(utxos, TransactionPreview { inputs, ..preview })
+            .await
+    }
+
+    // Selects inputs for paying `recipients`, leaving out the UTXOs on `excluded` leaf indices.
+    pub(super) fn select(
+        &self,
+        utxos: &Utxos,
+        recipients: Vec<(ReceivingAddress, NativeCurrencyAmount)>,
+        fee: NativeCurrencyAmount,
+        coin_selection: Option<CoinSelection>,
+        excluded: &[u64],
+    ) -> Result<TransactionPreview> {
+        if recipients.is_empty() {
+            return Err(Error::Validation("no recipients given".to_string()));
+        }
+        let amount = recipients
+            .iter()
+            .fold(NativeCurrencyAmount::zero(), |total, (_, amount)| {
+                total + *amount
+            });
+
+        let selector = coin_selection.unwrap_or(self.coin_selection);
+        let (inputs, change) = utxos.select_utxos(amount + fee, &selector, excluded)?;
+
+        Ok(TransactionPreview {
+            inputs,
+            recipients,
+            fee,
+            change,
+            balance_after: utxos
+                .spendable()?
+                .checked_sub(&(amount + fee))
+                .unwrap_or_else(NativeCurrencyAmount::zero),
+            check: None,
+        })
+    }
+
+    fn select_sweep(
+        utxos: &Utxos,
+        recipient: ReceivingAddress,
+        fee: NativeCurrencyAmount,
+        leaf_indices: Option<&[u64]>,
+    ) -> Result<TransactionPreview> {
+        let (inputs, amount) = utxos.sweep_utxos(fee, leaf_indices)?;
 
-        // Select UTXOs and prepare them for use.
-        let (locked_utxos, excess_amount) = utxos.select_utxos(amount + fee);
+        Ok(TransactionPreview {
+            inputs,
+            recipients: vec![(recipient, amount)],
+            fee,
+            change: NativeCurrencyAmount::zero(),
+            balance_after: utxos
+                .spendable()?
+                .checked_sub(&(amount + fee))
+                .unwrap_or_else(NativeCurrencyAmount::zero),
+            check: None,
+        })
+    }
+
+    // Builds the transaction for `preview`. Only with `commit` is the change key used up, which
+    // must happen before the transaction is submitted.
+    async fn build(
+        &self,
+        utxos: &Utxos,
+        preview: &TransactionPreview,
+        commit: bool,
+    ) -> Result<TransactionDetails> {
+        let mut keys = self.keys.write().await;
         let mut inputs = Vec::new();
 
-        for utxo in locked_utxos {
-            let keys = self.keys.read().await;
-            let unlocking_key = keys
-                .find_spending_key_for_utxo(&utxo.utxo)
-                .expect("to find key");
+        for utxo in &preview.inputs {
+            let unlocking_key = keys.find_spending_key_for_utxo(&utxo.utxo).ok_or_else(|| {
+                Error::Validation("no spending key for selected UTXO".to_string())
+            })?;
             inputs.push(UnlockedUtxo::unlock(
                 utxo.utxo.clone(),
                 unlocking_key.lock_script_and_witness(),
@@ -73,26 +241,33 @@ impl TransactionBuilder {
             ));
         }
 
-        // Prepare output UTXOs (including the change output).
-        let outputs = vec![
-            TxOutput::onchain_native_currency(
-                amount,
-                Digest::default(), // TODO: Proper generation (as this might leak privacy).
-                recipient,
+        // Prepare output UTXOs (including the change output, unless inputs matched exactly).
+        let nonce = transaction_nonce(&preview.inputs);
+        let mut outputs = Vec::new();
+        for (index, (recipient, amount)) in preview.recipients.iter().enumerate() {
+            outputs.push(TxOutput::onchain_native_currency(
+                *amount,
+                keys.sender_randomness(nonce, index, recipient.privacy_digest()),
+                recipient.clone(),
                 false,
-            ),
-            TxOutput::onchain_native_currency_as_change(
-                excess_amount,
-                Digest::default(), // TODO: Proper generation (as this might leak privacy).
-                self.keys
-                    .read()
-                    .await
-                    .current_key(KeyType::Symmetric)
-                    .to_address(), // TODO: increment symmetric index?
-            ),
-        ];
+            ));
+        }
+        if !preview.change.is_zero() {
+            // A fresh key per change output keeps our transactions unlinkable. It is persisted
+            // before submission, and the scanner's lookahead finds it again on rescan.
+            let change_address = if commit {
+                keys.derive_next_key(KeyType::Symmetric)?;
+                keys.current_key(KeyType::Symmetric)?.to_address()
+            } else {
+                keys.peek_next_key(KeyType::Symmetric).to_address()
+            };
+            outputs.push(TxOutput::onchain_native_currency_as_change(
+                preview.change,
+                keys.sender_randomness(nonce, outputs.len(), change_address.privacy_digest()),
+                change_address,
+            ));
+        }
 
-        // Prepare the PrimitiveWitness and then create a ProofCollection for announcing.
         info!(
             "Preparing transaction with {} inputs, {} outputs...",
             inputs.len(),
@@ -101,27 +276,148 @@ impl TransactionBuilder {
         let transaction = TransactionDetails::new_without_coinbase(
             inputs,
             outputs,
-            fee,
+            preview.fee,
             Timestamp::now(),
             utxos.msa.clone(),
-            Network::Main,
+            self.network,
         );
+        Ok(transaction)
+    }
+
+    async fn submit(
+        &self,
+        mut utxos: RwLockWriteGuard<'_, Utxos>,
+        preview: TransactionPreview,
+    ) -> Result<TransactionKernelId> {
+        let recipient_addresses = preview
+            .recipients
+            .iter()
+            .map(|(recipient, amount)| {
+                let address = recipient
+                    .to_bech32m(self.network)
+                    .map_err(|e| Error::Validation(format!("cannot encode recipient: {e}")))?;
+                Ok((address, *amount))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Prepare the PrimitiveWitness and then create a ProofCollection for announcing.
+        let transaction = self.build(&utxos, &preview, true).await?;
         let primitive_witness = transaction.primitive_witness();
         let transaction_kernel = primitive_witness.kernel.clone();
+        let kernel_id = transaction_kernel.txid();
+
+        // Lock the inputs so the UTXOs cache can be released during the long proving step.
+        utxos.reserve(&preview.inputs, kernel_id)?;
+        drop(utxos);
+
+        // Nothing left the wallet yet if reaching the node or proving fails.
+        let (proving_height, tx) = match self.prove_transaction(primitive_witness).await {
+            Ok(proven) => proven,
+            Err(e) => {
+                self.utxos.write().await.release(kernel_id)?;
+                return Err(e);
+            }
+        };
+
+        // A failed submission may still have reached the node, so only an explicit rejection
+        // hands the inputs back. Otherwise the transaction is tracked and expiry decides.
+        let submitted = self
+            .client
+            .call(RpcMethod::SubmitTransaction, |c| {
+                let tx = tx.clone();
+                async move { c.submit_transaction(tx).await }
+            })
+            .await;
+        let submitted_height = match submitted {
+            Ok(response) if response.success => {
+                info!("Succesfully submitted transaction!");
+                // Expiry counts from the tip at submission, which proving may have moved.
+                self.client.height().await.unwrap_or_else(|e| {
+                    warn!(
+                        "Failed to refresh the tip after submitting, expiry counts from before proving: {e}"
+                    );
+                    proving_height
+                })
+            }
+            Ok(_) => {
+                self.utxos.write().await.release(kernel_id)?;
+                return Err(Error::Validation(format!(
+                    "transaction {kernel_id} was rejected by the node"
+                )));
+            }
+            Err(e) => {
+                warn!(
+                    "Transaction {kernel_id} may not have reached the node, its inputs stay reserved until it is mined or expires."
+                );
+                self.track(
+                    &transaction_kernel,
+                    recipient_addresses,
+                    preview.fee,
+                    proving_height,
+                )
+                .await?;
+                return Err(e);
+            }
+        };
+
+        self.track(
+            &transaction_kernel,
+            recipient_addresses,
+            preview.fee,
+            submitted_height,
+        )
+        .await?;
+        Ok(kernel_id)
+    }
+
+    // Records a submitted transaction in the history and tracks it until it is mined.
+    async fn track(
+        &self,
+        transaction_kernel: &TransactionKernel,
+        recipients: Vec<(String, NativeCurrencyAmount)>,
+        fee: NativeCurrencyAmount,
+        submitted_height: BlockHeight,
+    ) -> Result<()> {
+        let kernel_id = transaction_kernel.txid();
+        self.utxos
+            .read()
+            .await
+            .record_sent(recipients.clone(), fee, kernel_id)?;
+        self.pending.write().await.submit(PendingTransaction {
+            kernel_id,
+            inputs: transaction_kernel
+                .inputs
+                .iter()
+                .map(|r| r.absolute_indices.clone())
+                .collect(),
+            recipients,
+            fee,
+            submitted_height,
+            status: PendingStatus::Pending,
+        })
+    }
+
+    // Proves the transaction, returning it along with the tip height read before proving.
+    async fn prove_transaction(
+        &self,
+        primitive_witness: PrimitiveWitness,
+    ) -> Result<(BlockHeight, RpcTransaction)> {
+        let transaction_kernel = primitive_witness.kernel.clone();
+        // Read before proving, so expiry has a starting point even if the node can't be reached
+        // once the transaction is out.
+        let proving_height = self.client.height().await?;
         let proof_collection = tokio::task::spawn_blocking(move || Self::prove(&primitive_witness))
             .await
-            .expect("proving task panicked");
+            .map_err(Error::proving)??;
 
         let tx = RpcTransaction {
             proof: RpcTransactionProof::ProofCollection(Box::new(proof_collection.into())),
             kernel: (&transaction_kernel).into(),
         };
-        self.client.submit_transaction(tx).await.unwrap();
-        info!("Succesfully submitted transaction!");
+        Ok((proving_height, tx))
     }
 
-    // TODO: Proper error handling without crashing...
-    fn prove(primitive_witness: &PrimitiveWitness) -> ProofCollection {
+    fn prove(primitive_witness: &PrimitiveWitness) -> Result<ProofCollection> {
         let (
             removal_records_integrity_witness,
             collect_lock_scripts_witness,
@@ -143,7 +439,7 @@ impl TransactionBuilder {
                 RemovalRecordsIntegrity.program(),
                 removal_records_integrity_witness.nondeterminism(),
             )
-            .expect("RemovalRecordsIntegrity proving failed");
+            .map_err(|e| Error::Proving(format!("RemovalRecordsIntegrity: {e:?}")))?;
             proof.into()
         };
 
@@ -155,7 +451,7 @@ impl TransactionBuilder {
                 CollectLockScripts.program(),
                 collect_lock_scripts_witness.nondeterminism(),
             )
-            .expect("CollectLockScripts proving failed");
+            .map_err(|e| Error::Proving(format!("CollectLockScripts: {e:?}")))?;
             proof.into()
         };
 
@@ -167,7 +463,7 @@ impl TransactionBuilder {
                 KernelToOutputs.program(),
                 kernel_to_outputs_witness.nondeterminism(),
             )
-            .expect("KernelToOutputs proving failed");
+            .map_err(|e| Error::Proving(format!("KernelToOutputs: {e:?}")))?;
             proof.into()
         };
 
@@ -179,7 +475,7 @@ impl TransactionBuilder {
                 CollectTypeScripts.program(),
                 collect_type_scripts_witness.nondeterminism(),
             )
-            .expect("CollectTypeScripts proving failed");
+            .map_err(|e| Error::Proving(format!("CollectTypeScripts: {e:?}")))?;
             proof.into()
         };
 
@@ -194,7 +490,7 @@ impl TransactionBuilder {
                 lsaw.program.clone(),
                 lsaw.nondeterminism(),
             )
-            .expect("LockScript proving failed");
+            .map_err(|e| Error::Proving(format!("LockScript: {e:?}")))?;
             lock_scripts_halt.push(proof.into());
         }
 
@@ -220,7 +516,7 @@ impl TransactionBuilder {
                 tsaw.program.clone(),
                 tsaw.nondeterminism(),
             )
-            .expect("Type script proving failed");
+            .map_err(|e| Error::Proving(format!("Type script: {e:?}")))?;
             type_scripts_halt.push(proof.into());
         }
 
@@ -238,7 +534,7 @@ impl TransactionBuilder {
             .kernel
             .mast_path(TransactionKernelField::MergeBit);
 
-        ProofCollection {
+        Ok(ProofCollection {
             removal_records_integrity,
             collect_lock_scripts,
             lock_scripts_halt,
@@ -251,6 +547,6 @@ impl TransactionBuilder {
             salted_inputs_hash,
             salted_outputs_hash,
             merge_bit_mast_path,
-        }
+        })
     }
 }
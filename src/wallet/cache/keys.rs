@@ -1,9 +1,9 @@
 use std::{collections::HashMap, sync::Arc};
 
 use neptune_privacy::{
-    api::export::{Announcement, KeyType, SpendingKey, Utxo},
+    api::export::{Announcement, BlockHeight, Digest, KeyType, SpendingKey, Tip5, Utxo},
     application::json_rpc::core::model::block::transaction_kernel::RpcChunkDictionary,
-    prelude::twenty_first::prelude::MmrMembershipProof,
+    prelude::{triton_vm::prelude::BFieldElement, twenty_first::prelude::MmrMembershipProof},
     state::wallet::wallet_entropy::WalletEntropy,
     util_types::mutator_set::ms_membership_proof::MsMembershipProof,
 };
@@ -12,56 +12,100 @@ use tracing::info;
 
 use crate::{
     core::storage::KeysKeyspace,
-    wallet::utils::announcement::{extract_ciphertext, extract_receiver_identifier},
+    error::{Error, Result},
+    wallet::{
+        cache::utxos::LockedUtxo,
+        utils::{
+            announcement::{extract_ciphertext, extract_receiver_identifier},
+            encryption::EncryptedMnemonic,
+        },
+    },
 };
 
 #[derive(Clone)]
 pub struct Keys {
     storage: KeysKeyspace,
     entropy: WalletEntropy,
+    // Every derived key, including the `lookahead` not-yet-used ones past each used count.
     keys: HashMap<KeyType, Vec<SpendingKey>>,
+    used: HashMap<KeyType, u64>,
+    // Routes an announcement's receiver identifier straight to the key it is meant for.
+    receiver_identifiers: HashMap<BFieldElement, (KeyType, u64)>,
+    lookahead: u64,
 }
 
 impl Keys {
-    pub fn new(storage: KeysKeyspace) -> Self {
+    pub fn new(storage: KeysKeyspace, passphrase: &str, lookahead: u64) -> Result<Self> {
         info!("Initializing keys cache...");
 
         let mnemonic = storage
-            .get_mnemonic()
-            .expect("wallet not initialized: no mnemonic found in storage");
+            .get_mnemonic()?
+            .ok_or_else(|| {
+                Error::Validation(
+                    "wallet not initialized: run `kelp create` or pass --mnemonic".to_string(),
+                )
+            })?
+            .decrypt(passphrase)?;
         let words: Vec<String> = mnemonic.split_whitespace().map(String::from).collect();
         let entropy = WalletEntropy::from_phrase(&words)
-            .expect("wallet storage corrupted: stored mnemonic is invalid");
+            .map_err(|_| Error::Corrupted("stored mnemonic is invalid".to_string()))?;
 
         let mut keys = Keys {
             storage,
             entropy,
             keys: HashMap::new(),
+            used: HashMap::new(),
+            receiver_identifiers: HashMap::new(),
+            lookahead,
         };
-        keys.load();
-        keys
+        keys.load()?;
+        Ok(keys)
     }
 
-    pub fn current_key(&self, key_type: KeyType) -> &SpendingKey {
-        self.keys
-            .get(&key_type)
-            .and_then(|keys| keys.last())
-            .unwrap()
+    // Re-encrypts the stored mnemonic under `new`, provided `current` unlocks it.
+    pub fn change_passphrase(&self, current: &str, new: &str) -> Result<()> {
+        let mnemonic = self
+            .storage
+            .get_mnemonic()?
+            .ok_or_else(|| Error::Corrupted("encrypted mnemonic is missing".to_string()))?
+            .decrypt(current)?;
+
+        self.storage
+            .set_mnemonic(&EncryptedMnemonic::encrypt(&mnemonic, new)?)
     }
 
-    pub fn derive_next_key(&mut self, key_type: KeyType) {
-        let index = self.keys.get(&key_type).map_or(0, |v| v.len() as u64);
+    pub fn current_key(&self, key_type: KeyType) -> Result<&SpendingKey> {
+        self.used[&key_type]
+            .checked_sub(1)
+            .and_then(|index| self.keys[&key_type].get(index as usize))
+            .ok_or_else(|| Error::Corrupted(format!("no {key_type:?} key is in use")))
+    }
 
-        let new_key = match key_type {
-            KeyType::Generation => self.entropy.nth_generation_spending_key(index).into(),
-            KeyType::Symmetric => self.entropy.nth_symmetric_key(index).into(),
-        };
+    // The key `derive_next_key` would make current, without marking it used.
+    pub fn peek_next_key(&self, key_type: KeyType) -> SpendingKey {
+        Self::derive_key(&self.entropy, key_type, self.used[&key_type])
+    }
 
-        self.keys
-            .entry(key_type)
-            .or_insert_with(Vec::new)
-            .push(new_key);
-        self.storage.increment(key_type);
+    pub fn derive_next_key(&mut self, key_type: KeyType) -> Result<()> {
+        let used = self.used[&key_type];
+        self.set_used(key_type, used + 1)
+    }
+
+    // Sender randomness of output `index` in the transaction with `nonce`, derived from the
+    // wallet entropy so it can be recovered from the seed alone.
+    pub fn sender_randomness(
+        &self,
+        nonce: Digest,
+        index: usize,
+        receiver_digest: Digest,
+    ) -> Digest {
+        let mut input = nonce.values().to_vec();
+        input.extend_from_slice(&receiver_digest.values());
+        input.push(BFieldElement::new(index as u64));
+
+        // The nonce already tells transactions apart, so no block height goes in.
+        self.entropy
+            .generate_sender_randomness(BlockHeight::genesis(), Tip5::hash_varlen(&input))
     }
 
     pub(crate) fn all_keys(&self) -> impl Iterator<Item = (KeyType, &SpendingKey)> {
@@ -70,34 +114,56 @@ impl Keys {
             .flat_map(|(k_type, list)| list.iter().map(move |k| (*k_type, k)))
     }
 
-    pub fn scan(&self, announcements: Vec<Announcement>) -> Vec<(Utxo, MsMembershipProof)> {
+    // Decrypts the announcements meant for our keys. Keys are only marked used through
+    // `mark_used`, so a forged announcement without an output can't advance the index.
+    pub fn scan(
+        &self,
+        announcements: Vec<Announcement>,
+    ) -> Result<Vec<(Utxo, MsMembershipProof, (KeyType, u64))>> {
         let mut utxos = Vec::new();
 
-        for (key_type, key) in self.all_keys() {
-            let receiver_identifier = key.receiver_identifier();
-            let found_utxos: Vec<(Utxo, MsMembershipProof)> = announcements
-                .iter()
-                .filter(|a| matches!(KeyType::try_from(*a), Ok(k) if k == key_type))
-                .filter(|a| matches!(extract_receiver_identifier(a), Some(i) if i == receiver_identifier))
-                .filter_map(|a| extract_ciphertext(&a))
-                .filter_map(|c| key.decrypt(&c).ok())
-                .map(|(utxo, sender_randomness)| {
-                    (
-                      utxo,
-                      MsMembershipProof {
-                        sender_randomness,
-                        receiver_preimage: key.privacy_preimage(),
-                        auth_path_aocl: MmrMembershipProof::new(vec![]),
-                        aocl_leaf_index: 0,
-                        target_chunks: RpcChunkDictionary::default().into()
-                      }
-                    )
-                })
-                .collect();
-            utxos.extend(found_utxos);
+        for announcement in &announcements {
+            let Some(&(key_type, index)) = extract_receiver_identifier(announcement)
+                .and_then(|i| self.receiver_identifiers.get(&i))
+            else {
+                continue;
+            };
+            if !matches!(KeyType::try_from(announcement), Ok(k) if k == key_type) {
+                continue;
+            }
+
+            let key = &self.keys[&key_type][index as usize];
+            let Some((utxo, sender_randomness)) =
+                extract_ciphertext(announcement).and_then(|c| key.decrypt(&c).ok())
+            else {
+                continue;
+            };
+
+            utxos.push((
+                utxo,
+                MsMembershipProof {
+                    sender_randomness,
+                    receiver_preimage: key.privacy_preimage(),
+                    auth_path_aocl: MmrMembershipProof::new(vec![]),
+                    aocl_leaf_index: 0,
+                    target_chunks: RpcChunkDictionary::default().into(),
+                },
+                (key_type, index),
+            ));
         }
 
-        utxos
+        Ok(utxos)
+    }
+
+    // Called once an announcement to key `index` is matched to an output. A payment to a
+    // lookahead key means it was handed out elsewhere, so the index advances past it.
+    pub fn mark_used(&mut self, (key_type, index): (KeyType, u64)) -> Result<()> {
+        if index >= self.used[&key_type] {
+            info!("Lookahead {key_type:?} key {index} received a payment, advancing index.");
+            self.set_used(key_type, index + 1)?;
+        }
+
+        Ok(())
     }
 
     pub fn find_spending_key_for_utxo(&self, utxo: &Utxo) -> Option<&SpendingKey> {
@@ -105,22 +171,157 @@ impl Keys {
             .find_map(|(_, k)| (k.lock_script_hash() == utxo.lock_script_hash()).then_some(k))
     }
 
-    fn load(&mut self) {
-        for key_type in [KeyType::Generation, KeyType::Symmetric] {
-            let current_index = self.storage.get(key_type);
+    // Marks the first `count` keys of `key_type` as used and keeps the lookahead window filled.
+    fn set_used(&mut self, key_type: KeyType, count: u64) -> Result<()> {
+        self.storage.set(key_type, count)?;
+        self.used.insert(key_type, count);
+        self.fill_lookahead(key_type);
+        Ok(())
+    }
 
-            for index in 0..current_index {
-                let key = match key_type {
-                    KeyType::Generation => self.entropy.nth_generation_spending_key(index).into(),
-                    KeyType::Symmetric => self.entropy.nth_symmetric_key(index).into(),
-                };
+    fn fill_lookahead(&mut self, key_type: KeyType) {
+        let target = self.used[&key_type] + self.lookahead;
+        let list = self.keys.entry(key_type).or_insert_with(Vec::new);
 
-                self.keys.entry(key_type).or_insert_with(Vec::new).push(key);
-            }
+        for index in list.len() as u64..target {
+            let key = Self::derive_key(&self.entropy, key_type, index);
+
+            self.receiver_identifiers
+                .insert(key.receiver_identifier(), (key_type, index));
+            list.push(key);
         }
+    }
 
-        info!("Loaded {} used keys.", self.all_keys().count());
+    // Takes the entropy alone so it can be used while `keys` is borrowed mutably.
+    fn derive_key(entropy: &WalletEntropy, key_type: KeyType, index: u64) -> SpendingKey {
+        match key_type {
+            KeyType::Generation => entropy.nth_generation_spending_key(index).into(),
+            KeyType::Symmetric => entropy.nth_symmetric_key(index).into(),
+        }
     }
+
+    fn load(&mut self) -> Result<()> {
+        for key_type in [KeyType::Generation, KeyType::Symmetric] {
+            self.used.insert(key_type, self.storage.get(key_type)?);
+            self.fill_lookahead(key_type);
+        }
+
+        info!(
+            "Loaded {} used keys with a lookahead of {}.",
+            self.used.values().sum::<u64>(),
+            self.lookahead
+        );
+        Ok(())
+    }
+}
+
+// A per-transaction nonce, hashed from the AOCL leaf indices and digests of its inputs. No UTXO
+// is spent twice, so no two transactions share it, and a rescan that finds the inputs again
+// recovers it without anything stored.
+pub fn transaction_nonce(inputs: &[LockedUtxo]) -> Digest {
+    let mut inputs: Vec<(u64, Digest)> = inputs
+        .iter()
+        .map(|input| {
+            (
+                input.membership_proof.aocl_leaf_index,
+                Tip5::hash(&input.utxo),
+            )
+        })
+        .collect();
+    inputs.sort_by_key(|(leaf_index, _)| *leaf_index);
+
+    let elements: Vec<BFieldElement> = inputs
+        .into_iter()
+        .flat_map(|(leaf_index, digest)| {
+            std::iter::once(BFieldElement::new(leaf_index)).chain(digest.values())
+        })
+        .collect();
+    Tip5::hash_varlen(&elements)
 }
 
 pub type KeysCache = Arc<RwLock<Keys>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::storage::Storage;
+
+    fn keys() -> Keys {
+        let storage = Storage::temporary();
+        let phrase = WalletEntropy::new_random().to_phrase().join(" ");
+        storage
+            .keys
+            .set_mnemonic(&EncryptedMnemonic::encrypt(&phrase, "passphrase").unwrap())
+            .unwrap();
+
+        Keys::new(storage.keys, "passphrase", 5).unwrap()
+    }
+
+    #[test]
+    fn malformed_announcements_are_ignored() {
+        let keys = keys();
+        let flag = BFieldElement::new(KeyType::Generation as u64);
+        let receiver_identifier = keys
+            .peek_next_key(KeyType::Generation)
+            .receiver_identifier();
+
+        let announcements = vec![
+            Announcement::new(vec![]),
+            Announcement::new(vec![flag]),
+            Announcement::new(vec![flag, receiver_identifier]),
+            Announcement::new(vec![BFieldElement::new(1), receiver_identifier]),
+            Announcement::new(vec![flag, BFieldElement::new(42), BFieldElement::new(1)]),
+            Announcement::new(vec![
+                flag,
+                receiver_identifier,
+                BFieldElement::new(1),
+                BFieldElement::new(2),
+            ]),
+        ];
+
+        assert!(keys.scan(announcements).unwrap().is_empty());
+        assert_eq!(keys.used[&KeyType::Generation], 1);
+    }
+
+    #[test]
+    fn mark_used_only_advances() {
+        let mut keys = keys();
+
+        keys.mark_used((KeyType::Generation, 3)).unwrap();
+        assert_eq!(keys.used[&KeyType::Generation], 4);
+        assert_eq!(keys.keys[&KeyType::Generation].len(), 4 + 5);
+
+        keys.mark_used((KeyType::Generation, 1)).unwrap();
+        assert_eq!(keys.used[&KeyType::Generation], 4);
+    }
+
+    #[test]
+    fn transaction_nonce_ignores_input_order() {
+        let (first, second) = (LockedUtxo::mock(1, 4), LockedUtxo::mock(2, 9));
+
+        assert_eq!(
+            transaction_nonce(&[first.clone(), second.clone()]),
+            transaction_nonce(&[second.clone(), first.clone()])
+        );
+        assert_ne!(
+            transaction_nonce(&[first.clone(), second]),
+            transaction_nonce(&[first])
+        );
+    }
+
+    #[test]
+    fn sender_randomness_is_unique_per_output() {
+        let keys = keys();
+        let nonce = transaction_nonce(&[LockedUtxo::mock(1, 4)]);
+        let receiver_digest = Digest::default();
+
+        assert_eq!(
+            keys.sender_randomness(nonce, 0, receiver_digest),
+            keys.sender_randomness(nonce, 0, receiver_digest)
+        );
+        assert_ne!(
+            keys.sender_randomness(nonce, 0, receiver_digest),
+            keys.sender_randomness(nonce, 1, receiver_digest)
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use neptune_privacy::{
+    api::export::{BlockHeight, NativeCurrencyAmount},
+    protocol::consensus::transaction::transaction_kernel::TransactionKernelId,
+    util_types::mutator_set::removal_record::absolute_index_set::AbsoluteIndexSet,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{core::storage::PendingKeyspace, error::Result};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PendingStatus {
+    Pending,
+    Confirmed { block_height: BlockHeight },
+    // Not mined within the expiry window: dropped from the mempool or invalidated.
+    Expired,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingTransaction {
+    pub kernel_id: TransactionKernelId,
+    // Absolute index sets of the inputs' removal records, which identify the spend on-chain.
+    pub inputs: Vec<AbsoluteIndexSet>,
+    pub recipients: Vec<(String, NativeCurrencyAmount)>,
+    pub fee: NativeCurrencyAmount,
+    pub submitted_height: BlockHeight,
+    pub status: PendingStatus,
+}
+
+#[derive(Clone)]
+pub struct Pending {
+    storage: PendingKeyspace,
+    // Transactions that may still be mined, so blocks don't go through the whole record.
+    // Expired ones stay too, as a late block can still confirm them.
+    open: Vec<PendingTransaction>,
+    // Expired transactions whose inputs were already handed back. Not persisted: releasing
+    // again after a restart finds no reservation left and does nothing.
+    released: Vec<TransactionKernelId>,
+    expire_after: u64,
+}
+
+impl Pending {
+    pub fn new(storage: PendingKeyspace, expire_after: u64) -> Result<Self> {
+        let open = storage
+            .iter()?
+            .into_iter()
+            .filter(|transaction| !matches!(transaction.status, PendingStatus::Confirmed { .. }))
+            .collect::<Vec<_>>();
+        info!("Loaded {} pending transactions.", open.len());
+
+        Ok(Pending {
+            storage,
+            open,
+            released: Vec::new(),
+            expire_after,
+        })
+    }
+
+    pub fn submit(&mut self, transaction: PendingTransaction) -> Result<()> {
+        info!(
+            "Tracking transaction {} until it is mined.",
+            transaction.kernel_id
+        );
+        self.storage.put(&transaction)?;
+        self.open.push(transaction);
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<PendingTransaction>> {
+        self.storage.iter()
+    }
+
+    // Confirms transactions whose inputs are all spent by the block at `height`, expired or not,
+    // and expires the ones that waited too long.
+    pub fn apply_block(&mut self, height: BlockHeight, spent: &[AbsoluteIndexSet]) -> Result<()> {
+        for transaction in &mut self.open {
+            if transaction.inputs.iter().all(|input| spent.contains(input)) {
+                info!(
+                    "Transaction {} was confirmed on block {height}.",
+                    transaction.kernel_id
+                );
+                transaction.status = PendingStatus::Confirmed {
+                    block_height: height,
+                };
+            } else if transaction.status == PendingStatus::Pending
+                && height.value() >= transaction.submitted_height.value() + self.expire_after
+            {
+                warn!(
+                    "Transaction {} is stuck after {} blocks, marking it expired.",
+                    transaction.kernel_id, self.expire_after
+                );
+                transaction.status = PendingStatus::Expired;
+            } else {
+                continue;
+            }
+
+            self.storage.put(transaction)?;
+        }
+
+        self.open
+            .retain(|transaction| !matches!(transaction.status, PendingStatus::Confirmed { .. }));
+        Ok(())
+    }
+
+    // Hands out the expired transactions whose inputs weren't released yet, once each. They stay
+    // tracked, so a late block mining them still marks them confirmed.
+    pub fn release_expired(&mut self) -> Vec<TransactionKernelId> {
+        let expired = self
+            .open
+            .iter()
+            .filter(|transaction| transaction.status == PendingStatus::Expired)
+            .map(|transaction| transaction.kernel_id)
+            .filter(|kernel_id| !self.released.contains(kernel_id))
+            .collect::<Vec<_>>();
+        self.released.extend(expired.iter().copied());
+
+        expired
+    }
+
+    // Puts transactions confirmed above `height` back to pending after a reorg.
+    pub fn rollback(&mut self, height: BlockHeight) -> Result<()> {
+        for mut transaction in self.storage.iter()? {
+            if matches!(transaction.status, PendingStatus::Confirmed { block_height } if block_height > height)
+            {
+                transaction.status = PendingStatus::Pending;
+                self.storage.put(&transaction)?;
+                self.released
+                    .retain(|kernel_id| *kernel_id != transaction.kernel_id);
+                self.open.push(transaction);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub type PendingCache = Arc<RwLock<Pending>>;
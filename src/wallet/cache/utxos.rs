@@ -1,8 +1,9 @@
 use std::sync::Arc;
 
 use neptune_privacy::{
-    api::export::{NativeCurrencyAmount, Tip5, Utxo},
+    api::export::{BlockHeight, KeyType, NativeCurrencyAmount, Timestamp, Tip5, Utxo},
     application::json_rpc::core::api::rpc::RpcApi,
+    protocol::consensus::transaction::transaction_kernel::TransactionKernelId,
     util_types::mutator_set::{
         ms_membership_proof::MsMembershipProof, mutator_set_accumulator::MutatorSetAccumulator,
     },
@@ -11,9 +12,21 @@ use num_traits::{Zero, ops::checked::CheckedSub};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 use tracing::info;
-use xnt_rpc_client::http::HttpClient;
 
-use crate::core::storage::{UtxoKey, UtxosKeyspace};
+use crate::{
+    core::{
+        rpc::{RpcClient, RpcMethod},
+        storage::{
+            HistoryIndexKeyspace, HistoryKey, HistoryKeyspace, ReservationsKeyspace, SpentKeyspace,
+            Storage, UtxoKey, UtxosKeyspace,
+        },
+    },
+    error::{Error, Result},
+    wallet::{
+        history::{HistoryEntry, HistoryEvent},
+        utils::selection::CoinSelector,
+    },
+};
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct LockedUtxo {
@@ -30,85 +43,411 @@ impl LockedUtxo {
     }
 }
 
+// A UTXO pruned as spent, along with the node's tip height when the spend was noticed.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SpentUtxo {
+    pub utxo: LockedUtxo,
+    pub spent_height: BlockHeight,
+}
+
+#[cfg(test)]
+impl LockedUtxo {
+    // A UTXO worth `coins` on `leaf_index`, with a membership proof that verifies nowhere.
+    pub fn mock(coins: u32, leaf_index: u64) -> Self {
+        use neptune_privacy::{
+            api::export::Digest,
+            application::json_rpc::core::model::block::transaction_kernel::RpcChunkDictionary,
+            prelude::twenty_first::prelude::MmrMembershipProof,
+        };
+
+        LockedUtxo::new(
+            Utxo::new_native_currency(Digest::default(), NativeCurrencyAmount::coins(coins)),
+            MsMembershipProof {
+                sender_randomness: Digest::default(),
+                receiver_preimage: Digest::default(),
+                auth_path_aocl: MmrMembershipProof::new(vec![]),
+                aocl_leaf_index: leaf_index,
+                target_chunks: RpcChunkDictionary::default().into(),
+            },
+        )
+    }
+}
+
 #[derive(Clone)]
 pub struct Utxos {
-    client: HttpClient,
+    client: RpcClient,
     storage: UtxosKeyspace,
+    // Spent UTXOs are kept for a while so a reorg orphaning their spend can restore them.
+    spent: SpentKeyspace,
+    history: HistoryKeyspace,
+    history_index: HistoryIndexKeyspace,
+    // UTXOs locked as inputs of a transaction that is still in flight.
+    reservations: ReservationsKeyspace,
     pub summary: NativeCurrencyAmount,
+    pub reserved: NativeCurrencyAmount,
     // TODO: guarantee UTXOs are always synced to this
     pub msa: MutatorSetAccumulator,
 }
 
 impl Utxos {
-    pub fn new(client: HttpClient, storage: UtxosKeyspace) -> Self {
+    pub fn new(client: RpcClient, storage: &Storage) -> Result<Self> {
         info!("Initializing UTXOs cache...");
 
         let mut utxos = Utxos {
             client,
-            storage,
+            storage: storage.utxos.clone(),
+            spent: storage.spent.clone(),
+            history: storage.history.clone(),
+            history_index: storage.history_index.clone(),
+            reservations: storage.reservations.clone(),
             summary: NativeCurrencyAmount::from_nau(0),
+            reserved: NativeCurrencyAmount::from_nau(0),
             msa: MutatorSetAccumulator::default(),
         };
-        utxos.load();
-        utxos
+        utxos.load()?;
+        Ok(utxos)
     }
 
-    // Select UTXOs to cover `amount`.
+    // The balance that isn't locked by a transaction in flight.
+    pub fn spendable(&self) -> Result<NativeCurrencyAmount> {
+        subtract(self.summary, self.reserved)
+    }
+
+    // Every unreserved, unlocked UTXO, along with the amount held back by timelocks.
+    pub fn spendable_utxos(&self) -> Result<(Vec<LockedUtxo>, NativeCurrencyAmount)> {
+        let now = Timestamp::now();
+        let mut candidates = Vec::new();
+        let mut timelocked = NativeCurrencyAmount::zero();
+
+        for (key, utxo) in self.storage.iter()? {
+            if self.reservations.get(key)?.is_some() {
+                continue;
+            }
+
+            if !utxo.utxo.can_spend_at(now) {
+                timelocked += utxo.utxo.get_native_currency_amount();
+                continue;
+            }
+
+            candidates.push(utxo);
+        }
+
+        Ok((candidates, timelocked))
+    }
+
+    // Select unreserved, unlocked UTXOs to cover `amount` using `selector`, leaving out the ones
+    // on `excluded` leaf indices, e.g. inputs already picked for another transaction.
     // Returns (selected_utxos, excess)
     pub fn select_utxos(
         &self,
         amount: NativeCurrencyAmount,
-    ) -> (Vec<LockedUtxo>, NativeCurrencyAmount) {
-        let mut selected_utxos = Vec::new();
-        let mut total_amount = NativeCurrencyAmount::zero();
+        selector: &dyn CoinSelector,
+        excluded: &[u64],
+    ) -> Result<(Vec<LockedUtxo>, NativeCurrencyAmount)> {
+        let (mut candidates, timelocked) = self.spendable_utxos()?;
+        candidates.retain(|c| !excluded.contains(&c.membership_proof.aocl_leaf_index));
+        let available = total_of(&candidates);
 
-        for (_, utxo) in self.storage.iter() {
-            if total_amount >= amount {
-                break;
-            }
+        let insufficient_funds = || Error::InsufficientFunds {
+            available,
+            requested: amount,
+            reserved: self.reserved,
+            timelocked,
+        };
 
-            let utxo_amount = utxo.utxo.get_native_currency_amount();
-            total_amount += utxo_amount;
-            selected_utxos.push(utxo.clone());
-        }
+        let selected_utxos = selector
+            .select(candidates, amount)
+            .ok_or_else(insufficient_funds)?;
+        let total_amount = total_of(&selected_utxos);
 
+        // A selector returning too little is treated like any other shortfall.
         let excess_amount = total_amount
             .checked_sub(&amount)
-            .expect("insufficient funds");
+            .ok_or_else(insufficient_funds)?;
 
-        (selected_utxos, excess_amount)
+        Ok((selected_utxos, excess_amount))
     }
 
-    pub fn record(&mut self, utxo: Utxo, membership_proof: MsMembershipProof) {
-        let utxo_key = UtxoKey::new(membership_proof.aocl_leaf_index, Tip5::hash(&utxo));
+    // Picks every spendable UTXO, or only those on `leaf_indices`, to be swept in one
+    // transaction paying `fee`. Returns (selected_utxos, amount left for the recipient).
+    pub fn sweep_utxos(
+        &self,
+        fee: NativeCurrencyAmount,
+        leaf_indices: Option<&[u64]>,
+    ) -> Result<(Vec<LockedUtxo>, NativeCurrencyAmount)> {
+        let (mut candidates, timelocked) = self.spendable_utxos()?;
+        if let Some(leaf_indices) = leaf_indices {
+            candidates.retain(|c| leaf_indices.contains(&c.membership_proof.aocl_leaf_index));
+            if let Some(missing) = leaf_indices.iter().find(|index| {
+                !candidates
+                    .iter()
+                    .any(|c| c.membership_proof.aocl_leaf_index == **index)
+            }) {
+                return Err(Error::Validation(format!(
+                    "no spendable UTXO on leaf index {missing}"
+                )));
+            }
+        }
+
+        let available = total_of(&candidates);
+        let amount = available
+            .checked_sub(&fee)
+            .filter(|amount| !amount.is_zero())
+            .ok_or(Error::InsufficientFunds {
+                available,
+                requested: fee,
+                reserved: self.reserved,
+                timelocked,
+            })?;
+
+        Ok((candidates, amount))
+    }
+
+    pub fn record(
+        &mut self,
+        utxo: Utxo,
+        membership_proof: MsMembershipProof,
+        block_height: BlockHeight,
+        (key_type, key_index): (KeyType, u64),
+    ) -> Result<()> {
+        let leaf_index = membership_proof.aocl_leaf_index;
+        let utxo_digest = Tip5::hash(&utxo);
         let utxo_amount = utxo.get_native_currency_amount();
 
-        if self
-            .storage
-            .put(utxo_key, LockedUtxo::new(utxo, membership_proof))
-        {
+        if self.storage.put(
+            UtxoKey::new(leaf_index, utxo_digest),
+            LockedUtxo::new(utxo, membership_proof),
+        )? {
             self.summary += utxo_amount;
+            if self
+                .reservations
+                .get(UtxoKey::new(leaf_index, utxo_digest))?
+                .is_some()
+            {
+                self.reserved += utxo_amount;
+            }
+        }
+
+        self.log(HistoryEvent::Received {
+            block_height,
+            leaf_index,
+            utxo_digest,
+            key_type,
+            key_index,
+            amount: utxo_amount,
+        })
+    }
+
+    // Looks `inputs` up again so they carry current membership proofs, failing if any of them
+    // was spent or reserved in the meantime.
+    pub fn refresh(&self, inputs: &[LockedUtxo]) -> Result<Vec<LockedUtxo>> {
+        inputs
+            .iter()
+            .map(|input| {
+                let leaf_index = input.membership_proof.aocl_leaf_index;
+                let key = UtxoKey::new(leaf_index, Tip5::hash(&input.utxo));
+                if self.reservations.get(key.clone())?.is_some() {
+                    return Err(Error::Validation(format!(
+                        "input on leaf index {leaf_index} is reserved by another transaction"
+                    )));
+                }
+
+                self.storage.get(key)?.ok_or_else(|| {
+                    Error::Validation(format!("input on leaf index {leaf_index} was spent"))
+                })
+            })
+            .collect()
+    }
+
+    // Locks `inputs` to the transaction `kernel_id` so they aren't selected again.
+    pub fn reserve(&mut self, inputs: &[LockedUtxo], kernel_id: TransactionKernelId) -> Result<()> {
+        for input in inputs {
+            let key = UtxoKey::new(
+                input.membership_proof.aocl_leaf_index,
+                Tip5::hash(&input.utxo),
+            );
+            self.reservations.put(key, kernel_id)?;
+            self.reserved += input.utxo.get_native_currency_amount();
+        }
+
+        Ok(())
+    }
+
+    // Unlocks every unspent UTXO reserved by `kernel_id`, once it failed or expired.
+    pub fn release(&mut self, kernel_id: TransactionKernelId) -> Result<()> {
+        for (key, reserved_by) in self.reservations.iter()? {
+            if reserved_by == kernel_id {
+                self.unreserve(key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Spent UTXOs keep their reservation until they are forgotten, see `prune`.
+    fn unreserve(&mut self, key: UtxoKey) -> Result<()> {
+        if self.reservations.get(key.clone())?.is_none() {
+            return Ok(());
+        }
+        let Some(utxo) = self.storage.get(key.clone())? else {
+            return Ok(());
+        };
+
+        self.reserved = subtract(self.reserved, utxo.utxo.get_native_currency_amount())?;
+        self.reservations.remove(key)
+    }
+
+    pub fn record_sent(
+        &self,
+        recipients: Vec<(String, NativeCurrencyAmount)>,
+        fee: NativeCurrencyAmount,
+        kernel_id: TransactionKernelId,
+    ) -> Result<()> {
+        self.log(HistoryEvent::Sent {
+            recipients,
+            fee,
+            kernel_id,
+        })
+    }
+
+    // Appends `event` to the ledger. A rescan finds the same UTXOs and spends again, so a UTXO
+    // is only logged as received or spent once.
+    fn log(&self, event: HistoryEvent) -> Result<()> {
+        let logged = history_key(&event)
+            .map(|key| self.history_index.contains(key))
+            .unwrap_or(Ok(false))?;
+        if logged {
+            return Ok(());
+        }
+
+        let sequence = self.history.push(HistoryEntry::new(event.clone()))?;
+        self.index(&event, sequence)
+    }
+
+    fn index(&self, event: &HistoryEvent, sequence: u64) -> Result<()> {
+        if let HistoryEvent::Reverted {
+            leaf_index,
+            utxo_digest,
+            ..
+        } = *event
+        {
+            // Dropped by a reorg, so it may be received again on the new chain.
+            self.history_index
+                .remove(HistoryKey::received(leaf_index, utxo_digest))?;
+        }
+
+        match history_key(event) {
+            Some(key) => self.history_index.put(key, sequence),
+            None => Ok(()),
         }
     }
 
-    pub async fn sync_proofs(&mut self) {
+    // Returns one page of the ledger, newest first, along with its total number of entries.
+    pub fn history(&self, offset: usize, limit: usize) -> Result<(Vec<HistoryEntry>, usize)> {
+        let entries = self
+            .history
+            .page(offset, limit)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+
+        Ok((entries, self.history.len()?))
+    }
+
+    // Drops every UTXO recorded at or above `leaf_index`, e.g. ones found in orphaned blocks, and
+    // restores the ones whose spend was noticed above `height`, as that spend may be orphaned
+    // too. A restored UTXO that is still spent is pruned again on the next sync.
+    // Returns how many UTXOs were dropped and restored.
+    pub fn rollback(&mut self, leaf_index: u64, height: BlockHeight) -> Result<(usize, usize)> {
+        let removed_utxos = self.storage.remove_from(leaf_index)?;
+
+        for utxo in &removed_utxos {
+            let amount = utxo.utxo.get_native_currency_amount();
+            self.summary = subtract(self.summary, amount)?;
+            // The UTXO is already gone from storage, so its reservation is dropped by hand.
+            let key = UtxoKey::new(
+                utxo.membership_proof.aocl_leaf_index,
+                Tip5::hash(&utxo.utxo),
+            );
+            if self.reservations.get(key.clone())?.is_some() {
+                self.reserved = subtract(self.reserved, amount)?;
+                self.reservations.remove(key)?;
+            }
+            self.log(HistoryEvent::Reverted {
+                leaf_index: utxo.membership_proof.aocl_leaf_index,
+                utxo_digest: Tip5::hash(&utxo.utxo),
+                amount,
+            })?;
+        }
+
+        let mut restored_count = 0;
+        for (key, spent) in self.spent.iter()? {
+            if spent.utxo.membership_proof.aocl_leaf_index >= leaf_index {
+                // Received in an orphaned block, so there is nothing to restore.
+                self.reservations.remove(key.clone())?;
+                self.spent.remove(key)?;
+            } else if spent.spent_height > height {
+                let amount = spent.utxo.utxo.get_native_currency_amount();
+                if self.reservations.get(key.clone())?.is_some() {
+                    self.reserved += amount;
+                }
+                self.storage.put(key.clone(), spent.utxo)?;
+                self.spent.remove(key)?;
+                self.summary += amount;
+                restored_count += 1;
+            }
+        }
+
+        Ok((removed_utxos.len(), restored_count))
+    }
+
+    // Forgets spent UTXOs noticed below `height`, which is beyond the reach of any reorg.
+    pub fn forget_spent(&self, height: BlockHeight) -> Result<()> {
+        for (key, spent) in self.spent.iter()? {
+            if spent.spent_height < height {
+                self.reservations.remove(key.clone())?;
+                self.spent.remove(key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Forgets every known UTXO so they can be rebuilt by a rescan.
+    pub fn clear(&mut self) -> Result<()> {
+        // Reservations are kept: their UTXOs count as reserved again once they are found.
+        self.storage.remove_from(0)?;
+        for (key, _) in self.spent.iter()? {
+            self.spent.remove(key)?;
+        }
+        self.summary = NativeCurrencyAmount::zero();
+        self.reserved = NativeCurrencyAmount::zero();
+        Ok(())
+    }
+
+    pub async fn sync_proofs(&mut self) -> Result<()> {
         let mut index_sets = Vec::new();
 
-        for (key, utxo) in self.storage.iter() {
-            index_sets.push(utxo.membership_proof.compute_indices(key.extract_digest()));
+        for (key, utxo) in self.storage.iter()? {
+            index_sets.push(utxo.membership_proof.compute_indices(key.extract_digest()?));
         }
 
         let membership_snapshot = self
             .client
-            .restore_membership_proof(index_sets)
-            .await
-            .unwrap()
+            .call(RpcMethod::RestoreMembershipProof, |c| {
+                let index_sets = index_sets.clone();
+                async move { c.restore_membership_proof(index_sets).await }
+            })
+            .await?
             .snapshot;
+        // The snapshot is at least as recent as the tip read right after it, so any spend it
+        // reveals happened at or below that height.
+        let synced_height = self.client.height().await?;
 
         let mut utxo_count = 0;
         for ((utxo_key, mut utxo), membership_proof) in self
             .storage
-            .iter()
+            .iter()?
             .zip(membership_snapshot.membership_proofs.into_iter())
         {
             utxo.membership_proof = membership_proof
@@ -117,9 +456,9 @@ impl Utxos {
                     utxo.membership_proof.sender_randomness,
                     utxo.membership_proof.receiver_preimage,
                 )
-                .unwrap();
+                .map_err(|e| Error::Corrupted(format!("invalid membership proof: {e:?}")))?;
 
-            self.storage.put(utxo_key, utxo);
+            self.storage.put(utxo_key, utxo)?;
             utxo_count += 1;
         }
 
@@ -127,12 +466,12 @@ impl Utxos {
             "Synced membership proofs of {} UTXOs successfully.",
             utxo_count
         );
-        self.prune(membership_snapshot.synced_mutator_set.into());
+        self.prune(membership_snapshot.synced_mutator_set.into(), synced_height)
     }
 
-    fn prune(&mut self, msa: MutatorSetAccumulator) {
-        for (key, utxo) in self.storage.iter() {
-            let is_available = msa.verify(key.extract_digest(), &utxo.membership_proof);
+    fn prune(&mut self, msa: MutatorSetAccumulator, height: BlockHeight) -> Result<()> {
+        for (key, utxo) in self.storage.iter()? {
+            let is_available = msa.verify(key.extract_digest()?, &utxo.membership_proof);
 
             if !is_available {
                 let amount = utxo.utxo.get_native_currency_amount();
@@ -141,27 +480,203 @@ impl Utxos {
                     utxo.membership_proof.aocl_leaf_index, amount
                 );
 
-                self.storage.remove(key);
-                self.summary = self.summary.checked_sub(&amount).unwrap();
+                self.log(HistoryEvent::Spent {
+                    leaf_index: utxo.membership_proof.aocl_leaf_index,
+                    utxo_digest: key.extract_digest()?,
+                    amount,
+                })?;
+
+                // The reservation stays with the spent UTXO, so if a reorg orphans the spend, the
+                // UTXO comes back locked to the transaction that may be mined again.
+                if self.reservations.get(key.clone())?.is_some() {
+                    self.reserved = subtract(self.reserved, amount)?;
+                }
+                self.spent.put(
+                    key.clone(),
+                    &SpentUtxo {
+                        utxo,
+                        spent_height: height,
+                    },
+                )?;
+                self.storage.remove(key)?;
+                self.summary = subtract(self.summary, amount)?;
             }
         }
 
         self.msa = msa;
+        Ok(())
     }
 
-    fn load(&mut self) {
+    fn load(&mut self) -> Result<()> {
         let mut utxo_count = 0;
 
-        for (_, utxo) in self.storage.iter() {
-            self.summary += utxo.utxo.get_native_currency_amount();
+        for (key, utxo) in self.storage.iter()? {
+            let amount = utxo.utxo.get_native_currency_amount();
+            self.summary += amount;
+            if self.reservations.get(key)?.is_some() {
+                self.reserved += amount;
+            }
             utxo_count += 1;
         }
 
         info!(
-            "Loaded {} UTXOs containing {} XNT.",
-            utxo_count, self.summary
+            "Loaded {} UTXOs containing {} XNT ({} XNT reserved).",
+            utxo_count, self.summary, self.reserved
         );
+        Ok(())
+    }
+}
+
+// Amounts are only subtracted from totals they were added to, so running short means the
+// cached totals no longer match storage.
+fn subtract(
+    total: NativeCurrencyAmount,
+    amount: NativeCurrencyAmount,
+) -> Result<NativeCurrencyAmount> {
+    total.checked_sub(&amount).ok_or_else(|| {
+        Error::Corrupted(format!(
+            "cannot take {amount} XNT from a total of {total} XNT"
+        ))
+    })
+}
+
+fn history_key(event: &HistoryEvent) -> Option<HistoryKey> {
+    match *event {
+        HistoryEvent::Received {
+            leaf_index,
+            utxo_digest,
+            ..
+        } => Some(HistoryKey::received(leaf_index, utxo_digest)),
+        HistoryEvent::Spent {
+            leaf_index,
+            utxo_digest,
+            ..
+        } => Some(HistoryKey::spent(leaf_index, utxo_digest)),
+        HistoryEvent::Reverted { .. } | HistoryEvent::Sent { .. } => None,
     }
 }
 
+fn total_of(utxos: &[LockedUtxo]) -> NativeCurrencyAmount {
+    utxos
+        .iter()
+        .map(|utxo| utxo.utxo.get_native_currency_amount())
+        .fold(NativeCurrencyAmount::zero(), |total, amount| total + amount)
+}
+
 pub type UtxosCache = Arc<RwLock<Utxos>>;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::core::rpc::RetryPolicy;
+
+    // Nothing here talks to a node, so the client points nowhere.
+    fn utxos() -> Utxos {
+        let client = RpcClient::new(
+            vec!["http://127.0.0.1:1".to_string()],
+            RetryPolicy {
+                timeout: Duration::from_secs(1),
+                attempts: 1,
+                base_delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+            },
+            0,
+            false,
+        );
+
+        Utxos::new(client, &Storage::temporary()).unwrap()
+    }
+
+    fn record(utxos: &mut Utxos, utxo: &LockedUtxo) {
+        utxos
+            .record(
+                utxo.utxo.clone(),
+                utxo.membership_proof.clone(),
+                BlockHeight::from(10u64),
+                (KeyType::Generation, 0),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn rescans_log_each_event_once() {
+        let mut utxos = utxos();
+        let utxo = LockedUtxo::mock(5, 3);
+
+        for _ in 0..2 {
+            record(&mut utxos, &utxo);
+            // The mock proof verifies against no mutator set, so the UTXO is pruned as spent.
+            utxos
+                .prune(MutatorSetAccumulator::default(), BlockHeight::from(12u64))
+                .unwrap();
+            assert!(utxos.summary.is_zero());
+            utxos.clear().unwrap();
+        }
+
+        let (entries, total) = utxos.history(0, 10).unwrap();
+        assert_eq!(total, 2);
+        assert!(matches!(entries[0].event, HistoryEvent::Spent { .. }));
+        assert!(matches!(entries[1].event, HistoryEvent::Received { .. }));
+    }
+
+    #[test]
+    fn reverted_utxos_can_be_received_again() {
+        let mut utxos = utxos();
+        let utxo = LockedUtxo::mock(5, 3);
+
+        record(&mut utxos, &utxo);
+        assert_eq!(utxos.rollback(3, BlockHeight::from(9u64)).unwrap(), (1, 0));
+        record(&mut utxos, &utxo);
+
+        let (entries, total) = utxos.history(0, 10).unwrap();
+        assert_eq!(total, 3);
+        assert!(matches!(entries[0].event, HistoryEvent::Received { .. }));
+        assert!(matches!(entries[1].event, HistoryEvent::Reverted { .. }));
+        assert_eq!(utxos.summary, NativeCurrencyAmount::coins(5));
+    }
+
+    #[test]
+    fn sweep_takes_everything_minus_fee() {
+        let mut utxos = utxos();
+        record(&mut utxos, &LockedUtxo::mock(3, 0));
+        record(&mut utxos, &LockedUtxo::mock(4, 1));
+
+        let (inputs, amount) = utxos
+            .sweep_utxos(NativeCurrencyAmount::coins(1), None)
+            .unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(amount, NativeCurrencyAmount::coins(6));
+    }
+
+    #[test]
+    fn sweep_takes_only_chosen_leaves() {
+        let mut utxos = utxos();
+        record(&mut utxos, &LockedUtxo::mock(3, 0));
+        record(&mut utxos, &LockedUtxo::mock(4, 1));
+
+        let (inputs, amount) = utxos
+            .sweep_utxos(NativeCurrencyAmount::coins(1), Some(&[1]))
+            .unwrap();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].membership_proof.aocl_leaf_index, 1);
+        assert_eq!(amount, NativeCurrencyAmount::coins(3));
+
+        assert!(matches!(
+            utxos.sweep_utxos(NativeCurrencyAmount::coins(1), Some(&[1, 7])),
+            Err(Error::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn sweep_must_leave_something_after_fee() {
+        let mut utxos = utxos();
+        record(&mut utxos, &LockedUtxo::mock(3, 0));
+
+        assert!(matches!(
+            utxos.sweep_utxos(NativeCurrencyAmount::coins(3), None),
+            Err(Error::InsufficientFunds { .. })
+        ));
+    }
+}
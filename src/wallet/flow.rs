@@ -1,76 +1,200 @@
-use std::{sync::Arc, time::Duration};
+use std::{path::Path, sync::Arc, time::Duration};
 
-use neptune_privacy::state::wallet::wallet_entropy::WalletEntropy;
+use neptune_privacy::{
+    api::export::{BlockHeight, Network},
+    state::wallet::wallet_entropy::WalletEntropy,
+};
 use tokio::sync::RwLock;
-use xnt_rpc_client::http::HttpClient;
+use tracing::{info, warn};
+use zeroize::Zeroizing;
 
 use crate::wallet::builder::transaction::TransactionBuilder;
 use crate::{
-    core::storage::{KeysKeyspace, Storage},
+    core::{
+        rpc::RpcClient,
+        storage::{KeysKeyspace, Storage, WalletKeyspace},
+    },
+    error::{Error, Result},
     wallet::{
         cache::{
             keys::{Keys, KeysCache},
+            pending::{Pending, PendingCache},
             utxos::{Utxos, UtxosCache},
         },
         scanner::Scanner,
+        utils::{encryption::EncryptedMnemonic, selection::CoinSelection},
     },
 };
 
+// Settings every wallet is opened with, as given on the command line.
+#[derive(Clone, Copy)]
+pub struct WalletConfig {
+    pub network: Network,
+    // Blocks fetched concurrently while scanning.
+    pub scan_window: usize,
+    // Not-yet-used keys of each type watched for payments.
+    pub lookahead: u64,
+    // Blocks a submitted transaction may stay unconfirmed before it is flagged as stuck.
+    pub expire_after: u64,
+    pub coin_selection: CoinSelection,
+}
+
 #[derive(Clone)]
 pub struct Wallet {
+    pub network: Network,
     pub keys: KeysCache,
     pub utxos: UtxosCache,
+    pub pending: PendingCache,
     pub scanner: Arc<Scanner>,
     pub transaction_builder: Arc<TransactionBuilder>,
 }
 
 impl Wallet {
-    pub fn new(client: HttpClient, mnemonic: Option<String>) -> Self {
-        let Storage {
-            keys,
-            utxos,
-            wallet,
-        } = Storage::new("./wallet");
-        Self::initialize_mnemonic(&keys, mnemonic);
+    pub fn new<P: AsRef<Path>>(
+        client: RpcClient,
+        path: P,
+        mnemonic: Option<String>,
+        passphrase: &str,
+        typed: bool,
+        birthday: Option<BlockHeight>,
+        config: WalletConfig,
+    ) -> Result<Self> {
+        let storage = Storage::new(path)?;
+        Self::check_network(&storage.wallet, config.network)?;
+        Self::initialize_mnemonic(&storage.keys, mnemonic, passphrase, birthday)?;
+        Self::encrypt_plaintext_mnemonic(&storage.keys, passphrase, typed)?;
+        let birthday = storage.keys.get_birthday()?;
 
-        let keys = Arc::new(RwLock::new(Keys::new(keys)));
-        let utxos = Arc::new(RwLock::new(Utxos::new(client.clone(), utxos)));
+        let keys = Arc::new(RwLock::new(Keys::new(
+            storage.keys.clone(),
+            passphrase,
+            config.lookahead,
+        )?));
+        let utxos = Arc::new(RwLock::new(Utxos::new(client.clone(), &storage)?));
+        let pending = Arc::new(RwLock::new(Pending::new(
+            storage.pending.clone(),
+            config.expire_after,
+        )?));
         let scanner = Arc::new(Scanner::new(
             client.clone(),
-            wallet,
+            &storage,
+            birthday,
+            config.scan_window,
             keys.clone(),
             utxos.clone(),
+            pending.clone(),
+        ));
+        let transaction_builder = Arc::new(TransactionBuilder::new(
+            client,
+            config.network,
+            config.coin_selection,
+            keys.clone(),
+            utxos.clone(),
+            pending.clone(),
         ));
-        let transaction_builder =
-            Arc::new(TransactionBuilder::new(client, keys.clone(), utxos.clone()));
 
-        Wallet {
+        Ok(Wallet {
+            network: config.network,
             keys,
             utxos,
+            pending,
             scanner,
             transaction_builder,
-        }
+        })
     }
 
     pub async fn main_loop(&self) {
         let mut interval = tokio::time::interval(Duration::from_secs(10));
         loop {
             interval.tick().await;
-            self.scanner.scan().await;
+            if let Err(e) = self.scanner.scan().await {
+                warn!("Scan failed, retrying on next tick: {e}.");
+            }
         }
     }
 
-    fn initialize_mnemonic(storage: &KeysKeyspace, mnemonic: Option<String>) {
+    // A wallet is bound to the network it was first opened on; older wallets are assumed to be
+    // on the network they are opened with next.
+    fn check_network(storage: &WalletKeyspace, network: Network) -> Result<()> {
+        match storage.get_network()? {
+            Some(stored) if stored != network => Err(Error::Validation(format!(
+                "wallet belongs to network {stored}, not {network}"
+            ))),
+            Some(_) => Ok(()),
+            None => storage.set_network(network),
+        }
+    }
+
+    fn initialize_mnemonic(
+        storage: &KeysKeyspace,
+        mnemonic: Option<String>,
+        passphrase: &str,
+        birthday: Option<BlockHeight>,
+    ) -> Result<()> {
         let Some(m) = mnemonic else {
-            return;
+            return Ok(());
         };
-        if storage.get_mnemonic().is_some() {
-            panic!("wallet already initialized; cannot overwrite mnemonic");
+        if storage.get_mnemonic()?.is_some() || storage.get_plaintext_mnemonic()?.is_some() {
+            return Err(Error::Validation(
+                "wallet already initialized; cannot overwrite mnemonic".to_string(),
+            ));
         }
 
         let words: Vec<String> = m.split_whitespace().map(String::from).collect();
-        WalletEntropy::from_phrase(&words).expect("mnemonic validation failed");
+        WalletEntropy::from_phrase(&words)
+            .map_err(|_| Error::Validation("invalid mnemonic".to_string()))?;
+
+        storage.set_mnemonic(&EncryptedMnemonic::encrypt(&m, passphrase)?)?;
+        if let Some(height) = birthday {
+            storage.set_birthday(height)?;
+        }
+        Ok(())
+    }
+
+    // Older versions stored the mnemonic unencrypted. A typo here would lock the funds away for
+    // good, so a `typed` passphrase must be entered again, and the stored ciphertext must decrypt
+    // back to the mnemonic before the plaintext is removed. One read from a file or the
+    // environment can't be asked for again, e.g. when running without a terminal.
+    fn encrypt_plaintext_mnemonic(
+        storage: &KeysKeyspace,
+        passphrase: &str,
+        typed: bool,
+    ) -> Result<()> {
+        if storage.get_mnemonic()?.is_some() {
+            return Ok(());
+        }
+        let Some(mnemonic) = storage.get_plaintext_mnemonic()?.map(Zeroizing::new) else {
+            return Ok(());
+        };
+
+        info!("This wallet's mnemonic is stored unencrypted and will be encrypted now.");
+        if typed {
+            let repeated =
+                Zeroizing::new(rpassword::prompt_password("Repeat wallet passphrase: ")?);
+            if *repeated != passphrase {
+                return Err(Error::Validation(
+                    "passphrases do not match; the mnemonic was left unencrypted".to_string(),
+                ));
+            }
+        }
+
+        storage.set_mnemonic(&EncryptedMnemonic::encrypt(&mnemonic, passphrase)?)?;
+        let decrypted = storage
+            .get_mnemonic()?
+            .ok_or_else(|| Error::Corrupted("encrypted mnemonic was not stored".to_string()))?
+            .decrypt(passphrase)?;
+        if decrypted != mnemonic {
+            return Err(Error::Corrupted(
+                "encrypted mnemonic does not decrypt to the stored one".to_string(),
+            ));
+        }
 
-        storage.set_mnemonic(&m);
+        storage.remove_plaintext_mnemonic()?;
+        warn!(
+            "Mnemonic encrypted. The plaintext copy may remain in the database's journal and \
+             segment files until they are compacted; move the funds to a new wallet if this disk \
+             may have been read by someone else."
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,50 @@
+use neptune_privacy::{
+    api::export::{BlockHeight, Digest, KeyType, NativeCurrencyAmount, Timestamp},
+    protocol::consensus::transaction::transaction_kernel::TransactionKernelId,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum HistoryEvent {
+    Received {
+        block_height: BlockHeight,
+        leaf_index: u64,
+        utxo_digest: Digest,
+        key_type: KeyType,
+        key_index: u64,
+        amount: NativeCurrencyAmount,
+    },
+    // A UTXO whose membership proof no longer verifies against the synced mutator set.
+    Spent {
+        leaf_index: u64,
+        utxo_digest: Digest,
+        amount: NativeCurrencyAmount,
+    },
+    // A received UTXO that was dropped because its block got orphaned.
+    Reverted {
+        leaf_index: u64,
+        utxo_digest: Digest,
+        amount: NativeCurrencyAmount,
+    },
+    Sent {
+        // Bech32m address and amount of every non-change output.
+        recipients: Vec<(String, NativeCurrencyAmount)>,
+        fee: NativeCurrencyAmount,
+        kernel_id: TransactionKernelId,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: Timestamp,
+    pub event: HistoryEvent,
+}
+
+impl HistoryEntry {
+    pub fn new(event: HistoryEvent) -> Self {
+        HistoryEntry {
+            timestamp: Timestamp::now(),
+            event,
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use neptune_privacy::api::export::BlockHeight;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::{
+    core::rpc::RpcClient,
+    error::{Error, Result},
+    wallet::flow::{Wallet, WalletConfig},
+};
+
+struct LoadedWallet {
+    wallet: Wallet,
+    main_loop: JoinHandle<()>,
+}
+
+// Named wallets living under one data directory, each with its own database and scan loop.
+pub struct WalletManager {
+    client: RpcClient,
+    data_dir: PathBuf,
+    config: WalletConfig,
+    wallets: Mutex<HashMap<String, LoadedWallet>>,
+    active: Mutex<Option<String>>,
+}
+
+impl WalletManager {
+    pub fn new(client: RpcClient, data_dir: PathBuf, config: WalletConfig) -> Self {
+        WalletManager {
+            client,
+            data_dir,
+            config,
+            wallets: Mutex::new(HashMap::new()),
+            active: Mutex::new(None),
+        }
+    }
+
+    // Lists every wallet in the data directory along with whether it is loaded.
+    pub fn list(&self) -> Result<Vec<(String, bool)>> {
+        if !self.data_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let wallets = lock(&self.wallets)?;
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.data_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let loaded = wallets.contains_key(&name);
+                names.push((name, loaded));
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.data_dir.join(name).exists()
+    }
+
+    // Creates the wallet `name` from the mnemonic `mnemonic` comes up with. A new wallet can't own
+    // anything older than the current tip, so the tip becomes its birthday.
+    pub async fn create(
+        &self,
+        name: &str,
+        passphrase: &str,
+        mnemonic: impl FnOnce() -> Result<String>,
+    ) -> Result<Wallet> {
+        Self::validate_name(name)?;
+        if self.exists(name) {
+            return Err(Error::Validation(format!("wallet {name} already exists")));
+        }
+
+        let birthday = self.client.height().await?;
+        // A new wallet has no plaintext mnemonic to migrate, so the passphrase isn't asked again.
+        self.load(name, Some(mnemonic()?), passphrase, false, Some(birthday))
+    }
+
+    // Opens the wallet `name`, initializing it with `mnemonic` if given, and starts scanning it.
+    // The first loaded wallet becomes the active one. `typed` tells whether the passphrase came
+    // from a prompt, so it can be asked for again before encrypting a plaintext mnemonic.
+    pub fn load(
+        &self,
+        name: &str,
+        mnemonic: Option<String>,
+        passphrase: &str,
+        typed: bool,
+        birthday: Option<BlockHeight>,
+    ) -> Result<Wallet> {
+        Self::validate_name(name)?;
+        if lock(&self.wallets)?.contains_key(name) {
+            return Err(Error::Validation(format!(
+                "wallet {name} is already loaded"
+            )));
+        }
+        if mnemonic.is_none() && !self.exists(name) {
+            return Err(Error::Validation(format!("wallet {name} does not exist")));
+        }
+
+        let path = self.data_dir.join(name);
+        let created = !path.exists();
+        let wallet = Wallet::new(
+            self.client.clone(),
+            &path,
+            mnemonic,
+            passphrase,
+            typed,
+            birthday,
+            self.config,
+        );
+        let wallet = match wallet {
+            Ok(wallet) => wallet,
+            Err(e) => {
+                // Don't leave a half-initialized wallet behind that can't be created again.
+                if created {
+                    fs::remove_dir_all(&path).unwrap_or_else(|cleanup| {
+                        warn!("Failed to remove {}: {cleanup}.", path.display())
+                    });
+                }
+                return Err(e);
+            }
+        };
+        let main_loop = tokio::runtime::Handle::current().spawn({
+            let wallet = wallet.clone();
+            async move { wallet.main_loop().await }
+        });
+
+        lock(&self.wallets)?.insert(
+            name.to_string(),
+            LoadedWallet {
+                wallet: wallet.clone(),
+                main_loop,
+            },
+        );
+        lock(&self.active)?.get_or_insert_with(|| name.to_string());
+        info!("Loaded wallet {name}.");
+
+        Ok(wallet)
+    }
+
+    // Stops scanning the wallet `name` and waits for sends and rescans still using it, so its
+    // database is closed once this returns.
+    pub async fn unload(&self, name: &str) -> Result<()> {
+        let loaded = {
+            let mut wallets = lock(&self.wallets)?;
+            let loaded = wallets
+                .remove(name)
+                .ok_or_else(|| Error::Validation(format!("wallet {name} is not loaded")))?;
+
+            let mut active = lock(&self.active)?;
+            if active.as_deref() == Some(name) {
+                *active = wallets.keys().next().cloned();
+            }
+            loaded
+        };
+
+        // The scan loop owns a clone of the wallet until its task is gone.
+        loaded.main_loop.abort();
+        let _ = loaded.main_loop.await;
+
+        let wallet = loaded.wallet;
+        let in_use = |wallet: &Wallet| {
+            Arc::strong_count(&wallet.scanner) > 1
+                || Arc::strong_count(&wallet.transaction_builder) > 1
+        };
+        if in_use(&wallet) {
+            info!("Waiting for running sends and rescans of wallet {name} to finish...");
+            while in_use(&wallet) {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        }
+        drop(wallet);
+
+        info!("Unloaded wallet {name}.");
+        Ok(())
+    }
+
+    pub fn select(&self, name: &str) -> Result<()> {
+        if !lock(&self.wallets)?.contains_key(name) {
+            return Err(Error::Validation(format!("wallet {name} is not loaded")));
+        }
+
+        *lock(&self.active)? = Some(name.to_string());
+        Ok(())
+    }
+
+    pub fn active(&self) -> Result<Option<(String, Wallet)>> {
+        let Some(name) = lock(&self.active)?.clone() else {
+            return Ok(None);
+        };
+        let wallet = lock(&self.wallets)?
+            .get(&name)
+            .map(|loaded| (name, loaded.wallet.clone()));
+
+        Ok(wallet)
+    }
+
+    fn validate_name(name: &str) -> Result<()> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        if !valid {
+            return Err(Error::Validation(format!(
+                "invalid wallet name {name}: use letters, digits, '-' and '_'"
+            )));
+        }
+        Ok(())
+    }
+}
+
+// A panic while the lock was held may have left the registry half-updated.
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| Error::Corrupted("wallet registry lock was poisoned".to_string()))
+}
@@ -1,5 +1,7 @@
 pub mod builder;
 pub mod cache;
 pub mod flow;
+pub mod history;
+pub mod manager;
 pub mod scanner;
 pub mod utils;
@@ -1,104 +1,445 @@
+use std::sync::Arc;
+
+use futures::{StreamExt, stream};
 use neptune_privacy::{
-    api::export::{Announcement, BlockHeight, Tip5},
+    api::export::{Announcement, BlockHeight, Digest, Tip5, Utxo},
     application::json_rpc::core::api::rpc::RpcApi,
     protocol::consensus::block::block_selector::BlockSelector,
+    util_types::mutator_set::{
+        ms_membership_proof::MsMembershipProof,
+        removal_record::{RemovalRecord, absolute_index_set::AbsoluteIndexSet},
+    },
 };
-use tracing::info;
-use xnt_rpc_client::http::HttpClient;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
 
 use crate::{
-    core::storage::WalletKeyspace,
-    wallet::cache::{keys::KeysCache, utxos::UtxosCache},
+    core::{
+        rpc::{RpcClient, RpcMethod},
+        storage::{BlocksKeyspace, QuarantineKeyspace, Storage, WalletKeyspace},
+    },
+    error::{Error, Result},
+    wallet::cache::{keys::KeysCache, pending::PendingCache, utxos::UtxosCache},
 };
 
+// Depth of the deepest reorg that is rolled back; a deeper one requires a rescan.
+const REORG_DEPTH: u64 = 100;
+
+// An announcement that decrypted under one of our keys but has no matching output in its block.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SuspiciousAnnouncement {
+    pub block_height: BlockHeight,
+    pub block_digest: Digest,
+    pub commitment: Digest,
+    pub utxo: Utxo,
+}
+
+struct FetchedBlock {
+    height: BlockHeight,
+    digest: Digest,
+    announcements: Vec<Announcement>,
+    inputs: Vec<AbsoluteIndexSet>,
+    outputs: Vec<Digest>,
+}
+
 #[derive(Clone)]
 pub struct Scanner {
-    client: HttpClient,
+    client: RpcClient,
     storage: WalletKeyspace,
+    blocks: BlocksKeyspace,
+    quarantine: QuarantineKeyspace,
+    birthday: BlockHeight,
+    window: usize,
+    scan_lock: Arc<Mutex<()>>,
     pub keys: KeysCache,
     pub utxos: UtxosCache,
+    pub pending: PendingCache,
 }
 
 impl Scanner {
     pub fn new(
-        client: HttpClient,
-        storage: WalletKeyspace,
+        client: RpcClient,
+        storage: &Storage,
+        birthday: BlockHeight,
+        window: usize,
         keys: KeysCache,
         utxos: UtxosCache,
+        pending: PendingCache,
     ) -> Self {
         Scanner {
-            client: client.clone(),
-            storage,
+            client,
+            storage: storage.wallet.clone(),
+            blocks: storage.blocks.clone(),
+            quarantine: storage.quarantine.clone(),
+            birthday,
+            window: window.max(1),
+            scan_lock: Arc::new(Mutex::new(())),
             keys,
             utxos,
+            pending,
         }
     }
 
-    pub fn height(&self) -> BlockHeight {
-        self.storage.get_height()
+    // Blocks below the wallet's birthday can't contain its UTXOs, so scanning never starts earlier.
+    pub fn height(&self) -> Result<BlockHeight> {
+        Ok(self.storage.get_height()?.max(self.birthday))
+    }
+
+    // Announcements that decrypted under our keys without a matching output, kept for
+    // inspection.
+    pub fn quarantined(&self) -> Result<Vec<SuspiciousAnnouncement>> {
+        self.quarantine.iter()
+    }
+
+    pub async fn scan(&self) -> Result<()> {
+        let _guard = self.scan_lock.lock().await;
+        self.scan_blocks().await
     }
 
-    // TODO: rewrite scanner with batching, reorg support etc.
-    pub async fn scan(&self) {
-        let remote_height = self.client.height().await.unwrap().height;
-        let mut start_height = self.storage.get_height();
+    // Drops every known UTXO and scans again from `height` (the birthday by default).
+    // UTXOs received below `height` won't be found again.
+    pub async fn rescan(&self, height: Option<BlockHeight>) -> Result<()> {
+        let _guard = self.scan_lock.lock().await;
+        let height = height.unwrap_or(self.birthday).max(self.birthday);
+        // Starting above the scanned height would skip the blocks in between for good.
+        let scanned_height = self.height()?;
+        if height > scanned_height {
+            return Err(Error::Validation(format!(
+                "cannot rescan from {height}, blocks are only scanned up to {scanned_height}"
+            )));
+        }
+
+        self.utxos.write().await.clear()?;
+        self.storage.set_height(height)?;
+        info!("Rescanning from height {height}...");
+
+        self.scan_blocks().await?;
+        info!("Rescan finished at height {}.", self.height()?);
+        Ok(())
+    }
+
+    async fn scan_blocks(&self) -> Result<()> {
+        let remote_height = self.client.height().await?;
+        self.client.cross_check(remote_height).await?;
+        let reorged = self.rollback_orphaned_blocks(remote_height).await?;
+        let mut start_height = self.height()?;
         let initial_height = start_height;
 
-        while start_height <= remote_height {
-            let current_height = start_height;
+        // Keep up to `window` blocks in flight while applying them strictly in height order.
+        // The height is saved after each block, so a pass failing halfway leaves every applied
+        // block within reach of the next reorg check. Blocks are fetched by height concurrently,
+        // so a reorg during the pass can mix both chains below the saved tip; the check walks
+        // back from the tip and stops at the first match, so it won't notice such a mix.
+        let mut blocks = stream::iter(start_height.value()..=remote_height.value())
+            .map(|height| self.fetch_block(height.into()))
+            .buffered(self.window);
 
-            let transaction_kernel = self
-                .client
-                .get_block_transaction_kernel(BlockSelector::Height(current_height))
-                .await
-                .unwrap()
-                .kernel
-                .unwrap();
-            let announcements: Vec<Announcement> = transaction_kernel
+        while let Some(block) = blocks.next().await {
+            let Some(block) = block? else {
+                break; // The node's tip moved backwards since we asked for its height.
+            };
+
+            self.apply_block(block).await?;
+            start_height = start_height.next();
+        }
+
+        if reorged || start_height > initial_height {
+            let mut utxos = self.utxos.write().await;
+            utxos.sync_proofs().await?;
+
+            // Inputs of expired transactions that the synced mutator set still shows unspent
+            // were not mined, so they can be selected again.
+            for kernel_id in self.pending.write().await.release_expired() {
+                utxos.release(kernel_id)?;
+            }
+
+            // Blocks and spends that deep can no longer be orphaned, so stop tracking them.
+            let horizon = BlockHeight::from(start_height.value().saturating_sub(REORG_DEPTH));
+            self.blocks.trim(horizon)?;
+            utxos.forget_spent(horizon)?;
+        }
+        Ok(())
+    }
+
+    async fn fetch_block(&self, height: BlockHeight) -> Result<Option<FetchedBlock>> {
+        // Fetch everything by digest so a block and its digest always come from the same chain.
+        let Some(digest) = self
+            .client
+            .call(RpcMethod::GetBlockDigest, |c| async move {
+                c.get_block_digest(BlockSelector::Height(height)).await
+            })
+            .await?
+            .digest
+        else {
+            return Ok(None);
+        };
+        let transaction_kernel = self
+            .client
+            .call(RpcMethod::GetBlockTransactionKernel, |c| async move {
+                c.get_block_transaction_kernel(BlockSelector::Digest(digest))
+                    .await
+            })
+            .await?
+            .kernel
+            .ok_or_else(|| Error::Rpc(format!("missing transaction kernel of block {height}")))?;
+
+        Ok(Some(FetchedBlock {
+            height,
+            digest,
+            announcements: transaction_kernel
                 .announcements
-                .clone()
                 .into_iter()
                 .map(Into::into)
-                .collect();
-
-            let utxos = self.keys.read().await.scan(announcements);
-
-            for (utxo, mut mock_proof) in utxos {
-                let commitment = mock_proof
-                    .addition_record(Tip5::hash(&utxo))
-                    .canonical_commitment;
-                let index = transaction_kernel
-                    .outputs
-                    .iter()
-                    .position(|r| r.0 == commitment)
-                    .unwrap(); // This might panic bcs of a malicious announcement.
-                info!(
-                    "Found {} on block {current_height} on index {}",
-                    commitment.to_hex(),
-                    index
-                );
-
-                let block_body = self
-                    .client
-                    .get_block_body(BlockSelector::Height(current_height))
+                .collect(),
+            inputs: transaction_kernel
+                .inputs
+                .into_iter()
+                .map(|r| RemovalRecord::from(r).absolute_indices)
+                .collect(),
+            outputs: transaction_kernel.outputs.iter().map(|r| r.0).collect(),
+        }))
+    }
+
+    async fn apply_block(&self, mut block: FetchedBlock) -> Result<()> {
+        let announcements = std::mem::take(&mut block.announcements);
+        let utxos = self.keys.read().await.scan(announcements)?;
+
+        if !utxos.is_empty() {
+            // A single body fetch covers every matching output of the block.
+            let digest = block.digest;
+            let block_body = self
+                .client
+                .call(RpcMethod::GetBlockBody, |c| async move {
+                    c.get_block_body(BlockSelector::Digest(digest)).await
+                })
+                .await?
+                .body
+                .ok_or_else(|| Error::Rpc(format!("missing body of block {}", block.height)))?;
+            let first_leaf_index = block_body
+                .mutator_set_accumulator
+                .aocl
+                .leaf_count
+                .checked_sub(block.outputs.len() as u64)
+                .ok_or_else(|| {
+                    Error::Rpc(format!(
+                        "block {} has more outputs than leaves",
+                        block.height
+                    ))
+                })?;
+
+            for (utxo, mock_proof, key) in utxos {
+                let Some((utxo, membership_proof)) =
+                    locate_output(&self.quarantine, &block, first_leaf_index, utxo, mock_proof)?
+                else {
+                    continue;
+                };
+
+                self.utxos
+                    .write()
                     .await
-                    .unwrap()
-                    .body
-                    .unwrap();
+                    .record(utxo, membership_proof, block.height, key)?;
+                self.keys.write().await.mark_used(key)?;
+            }
+        }
 
-                mock_proof.aocl_leaf_index = block_body.mutator_set_accumulator.aocl.leaf_count
-                    - transaction_kernel.outputs.len() as u64
-                    + index as u64;
+        self.pending
+            .write()
+            .await
+            .apply_block(block.height, &block.inputs)?;
+
+        self.blocks.put(block.height, block.digest)?;
+        self.storage.set_height(block.height.next())
+    }
 
-                self.utxos.write().await.record(utxo, mock_proof);
+    // Walks back from the last scanned block until its digest is on the node's canonical chain,
+    // then undoes everything recorded above that fork point. Returns whether a reorg was found.
+    // A node lagging behind us, e.g. after failing over, can only vouch for blocks up to
+    // `remote_height`, and only a different digest counts as a fork, not a missing one.
+    async fn rollback_orphaned_blocks(&self, remote_height: BlockHeight) -> Result<bool> {
+        let Some(scanned_height) = self.height()?.value().checked_sub(1) else {
+            return Ok(false);
+        };
+        let tip_height = scanned_height.min(remote_height.value());
+
+        // Wallets scanned before block digests were recorded have nothing to compare against.
+        if self.blocks.get(tip_height.into())?.is_none() {
+            return Ok(false);
+        }
+
+        let mut fork_height = tip_height;
+        loop {
+            let Some(stored_digest) = self.blocks.get(fork_height.into())? else {
+                // Nothing is recorded below the birthday, so that is as far as a rollback goes.
+                if fork_height < self.birthday.value() {
+                    break;
+                }
+                return Err(Error::Validation(format!(
+                    "reorg reaches below block {fork_height}, deeper than the {REORG_DEPTH} blocks \
+                     tracked; rescan from an earlier height"
+                )));
+            };
+            let canonical_digest = self
+                .client
+                .call(RpcMethod::GetBlockDigest, |c| async move {
+                    c.get_block_digest(BlockSelector::Height(fork_height.into()))
+                        .await
+                })
+                .await?
+                .digest;
+
+            match canonical_digest {
+                Some(digest) if digest != stored_digest => {}
+                _ => break,
             }
+            fork_height = fork_height.checked_sub(1).ok_or_else(|| {
+                Error::Validation("node does not share our genesis block".to_string())
+            })?;
+        }
 
-            start_height = start_height.next();
+        if fork_height == tip_height {
+            return Ok(false);
         }
 
-        if start_height > initial_height {
-            self.utxos.write().await.sync_proofs().await;
-            self.storage.set_height(start_height);
+        let fork_body = self
+            .client
+            .call(RpcMethod::GetBlockBody, |c| async move {
+                c.get_block_body(BlockSelector::Height(fork_height.into()))
+                    .await
+            })
+            .await?
+            .body
+            .ok_or_else(|| Error::Rpc(format!("missing body of block {fork_height}")))?;
+        let (removed_count, restored_count) = self.utxos.write().await.rollback(
+            fork_body.mutator_set_accumulator.aocl.leaf_count,
+            fork_height.into(),
+        )?;
+
+        warn!(
+            "Reorg detected: rolled back {} blocks to height {fork_height}, dropping {} UTXOs and \
+             restoring {} spent ones.",
+            scanned_height - fork_height,
+            removed_count,
+            restored_count
+        );
+
+        self.pending.write().await.rollback(fork_height.into())?;
+        self.blocks.truncate(fork_height.into())?;
+        self.storage
+            .set_height(BlockHeight::from(fork_height).next())?;
+        Ok(true)
+    }
+}
+
+// Places the membership proof of `utxo` on the output it was announced for. An announcement
+// without a matching output is quarantined and `None` is returned.
+fn locate_output(
+    quarantine: &QuarantineKeyspace,
+    block: &FetchedBlock,
+    first_leaf_index: u64,
+    utxo: Utxo,
+    mut mock_proof: MsMembershipProof,
+) -> Result<Option<(Utxo, MsMembershipProof)>> {
+    let commitment = mock_proof
+        .addition_record(Tip5::hash(&utxo))
+        .canonical_commitment;
+    let Some(index) = block.outputs.iter().position(|c| *c == commitment) else {
+        warn!(
+            "Suspicious announcement on block {}: no output matches {}, quarantining it.",
+            block.height,
+            commitment.to_hex()
+        );
+        quarantine.put(SuspiciousAnnouncement {
+            block_height: block.height,
+            block_digest: block.digest,
+            commitment,
+            utxo,
+        })?;
+        return Ok(None);
+    };
+    info!(
+        "Found {} on block {} on index {}",
+        commitment.to_hex(),
+        block.height,
+        index
+    );
+
+    mock_proof.aocl_leaf_index = first_leaf_index + index as u64;
+    Ok(Some((utxo, mock_proof)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::storage::Storage, wallet::cache::utxos::LockedUtxo};
+
+    fn block(outputs: Vec<Digest>) -> FetchedBlock {
+        FetchedBlock {
+            height: BlockHeight::from(7u64),
+            digest: Digest::default(),
+            announcements: Vec::new(),
+            inputs: Vec::new(),
+            outputs,
         }
     }
+
+    fn commitment_of(utxo: &LockedUtxo) -> Digest {
+        utxo.membership_proof
+            .addition_record(Tip5::hash(&utxo.utxo))
+            .canonical_commitment
+    }
+
+    #[test]
+    fn announcement_without_output_is_quarantined() {
+        let storage = Storage::temporary();
+        let announced = LockedUtxo::mock(1, 0);
+        let block = block(vec![commitment_of(&LockedUtxo::mock(2, 0))]);
+
+        let located = locate_output(
+            &storage.quarantine,
+            &block,
+            0,
+            announced.utxo.clone(),
+            announced.membership_proof.clone(),
+        )
+        .unwrap();
+
+        assert!(located.is_none());
+        assert!(
+            storage
+                .quarantine
+                .get(block.height, commitment_of(&announced))
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn announcement_is_placed_on_its_output() {
+        let storage = Storage::temporary();
+        let announced = LockedUtxo::mock(1, 0);
+        let block = block(vec![
+            commitment_of(&LockedUtxo::mock(2, 0)),
+            commitment_of(&announced),
+        ]);
+
+        let (_, membership_proof) = locate_output(
+            &storage.quarantine,
+            &block,
+            10,
+            announced.utxo.clone(),
+            announced.membership_proof.clone(),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(membership_proof.aocl_leaf_index, 11);
+        assert!(
+            storage
+                .quarantine
+                .get(block.height, commitment_of(&announced))
+                .unwrap()
+                .is_none()
+        );
+    }
 }
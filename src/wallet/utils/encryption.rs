@@ -0,0 +1,110 @@
+use aes_gcm::{
+    Aes256Gcm, Nonce,
+    aead::{Aead, KeyInit},
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::error::{Error, Result};
+
+// A mnemonic encrypted with AES-256-GCM under an Argon2id key derived from a passphrase.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EncryptedMnemonic {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl EncryptedMnemonic {
+    pub fn encrypt(mnemonic: &str, passphrase: &str) -> Result<Self> {
+        let mut encrypted = EncryptedMnemonic {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+            salt: rand::random::<[u8; 16]>().to_vec(),
+            nonce: rand::random::<[u8; 12]>().to_vec(),
+            ciphertext: Vec::new(),
+        };
+
+        encrypted.ciphertext = encrypted
+            .cipher(passphrase)?
+            .encrypt(Nonce::from_slice(&encrypted.nonce), mnemonic.as_bytes())
+            .map_err(|_| Error::Validation("mnemonic encryption failed".to_string()))?;
+        Ok(encrypted)
+    }
+
+    pub fn decrypt(&self, passphrase: &str) -> Result<Zeroizing<String>> {
+        if self.nonce.len() != 12 {
+            return Err(Error::Corrupted("invalid mnemonic nonce".to_string()));
+        }
+
+        let plaintext = self
+            .cipher(passphrase)?
+            .decrypt(Nonce::from_slice(&self.nonce), self.ciphertext.as_ref())
+            .map_err(|_| Error::Validation("wrong passphrase".to_string()))?;
+
+        String::from_utf8(plaintext)
+            .map(Zeroizing::new)
+            .map_err(|_| Error::Corrupted("decrypted mnemonic is not valid UTF-8".to_string()))
+    }
+
+    fn cipher(&self, passphrase: &str) -> Result<Aes256Gcm> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| Error::Corrupted(format!("invalid KDF parameters: {e}")))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+            .map_err(|e| Error::Validation(format!("key derivation failed: {e}")))?;
+
+        Aes256Gcm::new_from_slice(key.as_ref())
+            .map_err(|_| Error::Validation("invalid key length".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MNEMONIC: &str = "abandon ability able about above absent absorb abstract absurd \
+                            abuse access accident";
+
+    #[test]
+    fn round_trip() {
+        let encrypted = EncryptedMnemonic::encrypt(MNEMONIC, "passphrase").unwrap();
+
+        assert_ne!(encrypted.ciphertext, MNEMONIC.as_bytes());
+        assert_eq!(encrypted.decrypt("passphrase").unwrap().as_str(), MNEMONIC);
+    }
+
+    #[test]
+    fn survives_serialization() {
+        let encrypted = EncryptedMnemonic::encrypt(MNEMONIC, "passphrase").unwrap();
+        let stored: EncryptedMnemonic =
+            serde_json::from_slice(&serde_json::to_vec(&encrypted).unwrap()).unwrap();
+
+        assert_eq!(stored.decrypt("passphrase").unwrap().as_str(), MNEMONIC);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let encrypted = EncryptedMnemonic::encrypt(MNEMONIC, "passphrase").unwrap();
+
+        assert!(matches!(
+            encrypted.decrypt("passphrase "),
+            Err(Error::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let mut encrypted = EncryptedMnemonic::encrypt(MNEMONIC, "passphrase").unwrap();
+        encrypted.ciphertext[0] ^= 1;
+
+        assert!(encrypted.decrypt("passphrase").is_err());
+    }
+}
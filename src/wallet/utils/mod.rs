@@ -1 +1,3 @@
 pub mod announcement;
+pub mod encryption;
+pub mod selection;
@@ -0,0 +1,316 @@
+use std::str::FromStr;
+
+use neptune_privacy::api::export::NativeCurrencyAmount;
+use num_traits::Zero;
+use rand::seq::SliceRandom;
+use tracing::info;
+
+use crate::wallet::cache::utxos::LockedUtxo;
+
+// Upper bound on the branches explored by branch-and-bound before giving up on an exact match.
+const BRANCH_AND_BOUND_TRIES: usize = 100_000;
+
+// Picks which spendable UTXOs fund a transaction of `target` (amount plus fee).
+pub trait CoinSelector: Send + Sync {
+    // Returns a subset of `candidates` worth at least `target`, or `None` if there is none.
+    fn select(
+        &self,
+        candidates: Vec<LockedUtxo>,
+        target: NativeCurrencyAmount,
+    ) -> Option<Vec<LockedUtxo>>;
+}
+
+// Spends UTXOs in the order they are stored, i.e. by AOCL leaf index, oldest first.
+pub struct FirstFit;
+
+// Spends the biggest UTXOs first, keeping the number of inputs low.
+pub struct LargestFirst;
+
+// Spends the smallest UTXOs first, consolidating dust.
+pub struct SmallestFirst;
+
+// Looks for a subset matching `target` exactly so no change output is needed, and falls back
+// to largest-first when there is none.
+pub struct BranchAndBound;
+
+// Spends UTXOs in random order so inputs don't reveal the wallet's age or structure.
+pub struct RandomOrder;
+
+impl CoinSelector for FirstFit {
+    fn select(
+        &self,
+        candidates: Vec<LockedUtxo>,
+        target: NativeCurrencyAmount,
+    ) -> Option<Vec<LockedUtxo>> {
+        accumulate(candidates, target)
+    }
+}
+
+impl CoinSelector for LargestFirst {
+    fn select(
+        &self,
+        mut candidates: Vec<LockedUtxo>,
+        target: NativeCurrencyAmount,
+    ) -> Option<Vec<LockedUtxo>> {
+        candidates.sort_by_key(|c| std::cmp::Reverse(amount_of(c)));
+        accumulate(candidates, target)
+    }
+}
+
+impl CoinSelector for SmallestFirst {
+    fn select(
+        &self,
+        mut candidates: Vec<LockedUtxo>,
+        target: NativeCurrencyAmount,
+    ) -> Option<Vec<LockedUtxo>> {
+        candidates.sort_by_key(amount_of);
+        accumulate(candidates, target)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(
+        &self,
+        mut candidates: Vec<LockedUtxo>,
+        target: NativeCurrencyAmount,
+    ) -> Option<Vec<LockedUtxo>> {
+        candidates.sort_by_key(|c| std::cmp::Reverse(amount_of(c)));
+        let amounts: Vec<i128> = candidates.iter().map(amount_of).collect();
+
+        match exact_match(&amounts, target.to_nau()) {
+            Some(picked) => Some(
+                candidates
+                    .into_iter()
+                    .zip(picked)
+                    .filter_map(|(candidate, picked)| picked.then_some(candidate))
+                    .collect(),
+            ),
+            None => {
+                info!("No exact match for {target} XNT, falling back to largest-first.");
+                LargestFirst.select(candidates, target)
+            }
+        }
+    }
+}
+
+impl CoinSelector for RandomOrder {
+    fn select(
+        &self,
+        mut candidates: Vec<LockedUtxo>,
+        target: NativeCurrencyAmount,
+    ) -> Option<Vec<LockedUtxo>> {
+        candidates.shuffle(&mut rand::rng());
+        accumulate(candidates, target)
+    }
+}
+
+// The built-in strategies, as chosen on the command line or per send. First-fit stays the
+// default, as that is how inputs were picked before strategies could be chosen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CoinSelection {
+    #[default]
+    FirstFit,
+    LargestFirst,
+    SmallestFirst,
+    BranchAndBound,
+    Random,
+}
+
+impl CoinSelector for CoinSelection {
+    fn select(
+        &self,
+        candidates: Vec<LockedUtxo>,
+        target: NativeCurrencyAmount,
+    ) -> Option<Vec<LockedUtxo>> {
+        match self {
+            CoinSelection::FirstFit => FirstFit.select(candidates, target),
+            CoinSelection::LargestFirst => LargestFirst.select(candidates, target),
+            CoinSelection::SmallestFirst => SmallestFirst.select(candidates, target),
+            CoinSelection::BranchAndBound => BranchAndBound.select(candidates, target),
+            CoinSelection::Random => RandomOrder.select(candidates, target),
+        }
+    }
+}
+
+impl FromStr for CoinSelection {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        <CoinSelection as clap::ValueEnum>::from_str(input.trim(), true)
+    }
+}
+
+fn amount_of(candidate: &LockedUtxo) -> i128 {
+    candidate.utxo.get_native_currency_amount().to_nau()
+}
+
+// Takes candidates in order until they cover `target`.
+fn accumulate(
+    candidates: Vec<LockedUtxo>,
+    target: NativeCurrencyAmount,
+) -> Option<Vec<LockedUtxo>> {
+    let mut total = NativeCurrencyAmount::zero();
+    let mut selected = Vec::new();
+
+    for candidate in candidates {
+        if total >= target {
+            break;
+        }
+        total += candidate.utxo.get_native_currency_amount();
+        selected.push(candidate);
+    }
+
+    (total >= target).then_some(selected)
+}
+
+// Depth-first search over include/exclude decisions for `amounts` (sorted descending), pruning
+// branches that overshoot `target` or can no longer reach it.
+fn exact_match(amounts: &[i128], target: i128) -> Option<Vec<bool>> {
+    let mut remaining: Vec<i128> = amounts
+        .iter()
+        .rev()
+        .scan(0, |sum, amount| {
+            *sum += amount;
+            Some(*sum)
+        })
+        .collect();
+    remaining.reverse();
+
+    let mut picked = vec![false; amounts.len()];
+    let mut tries = 0;
+    search(amounts, &remaining, target, 0, &mut picked, &mut tries).then_some(picked)
+}
+
+fn search(
+    amounts: &[i128],
+    remaining: &[i128],
+    target: i128,
+    index: usize,
+    picked: &mut [bool],
+    tries: &mut usize,
+) -> bool {
+    if target == 0 {
+        return true;
+    }
+    *tries += 1;
+    if index == amounts.len() || target < 0 || remaining[index] < target {
+        return false;
+    }
+    if *tries > BRANCH_AND_BOUND_TRIES {
+        return false;
+    }
+
+    picked[index] = true;
+    if search(
+        amounts,
+        remaining,
+        target - amounts[index],
+        index + 1,
+        picked,
+        tries,
+    ) {
+        return true;
+    }
+    picked[index] = false;
+    search(amounts, remaining, target, index + 1, picked, tries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One candidate per amount, on leaf indices following their order.
+    fn candidates(coins: &[u32]) -> Vec<LockedUtxo> {
+        coins
+            .iter()
+            .enumerate()
+            .map(|(leaf_index, coins)| LockedUtxo::mock(*coins, leaf_index as u64))
+            .collect()
+    }
+
+    fn leaf_indices(selected: Option<Vec<LockedUtxo>>) -> Vec<u64> {
+        let mut indices: Vec<u64> = selected
+            .expect("no selection")
+            .iter()
+            .map(|utxo| utxo.membership_proof.aocl_leaf_index)
+            .collect();
+        indices.sort();
+        indices
+    }
+
+    #[test]
+    fn first_fit_keeps_candidate_order() {
+        let selected = FirstFit.select(candidates(&[1, 5, 2, 8]), NativeCurrencyAmount::coins(7));
+        assert_eq!(leaf_indices(selected), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn largest_first_uses_fewest_inputs() {
+        let selected =
+            LargestFirst.select(candidates(&[1, 5, 2, 8]), NativeCurrencyAmount::coins(9));
+        assert_eq!(leaf_indices(selected), vec![1, 3]);
+    }
+
+    #[test]
+    fn smallest_first_spends_dust() {
+        let selected =
+            SmallestFirst.select(candidates(&[1, 5, 2, 8]), NativeCurrencyAmount::coins(3));
+        assert_eq!(leaf_indices(selected), vec![0, 2]);
+    }
+
+    #[test]
+    fn branch_and_bound_finds_exact_match() {
+        // Largest-first would take 5 and 4, leaving change.
+        let selected =
+            BranchAndBound.select(candidates(&[5, 4, 3, 2]), NativeCurrencyAmount::coins(6));
+        assert_eq!(leaf_indices(selected), vec![1, 3]);
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_to_largest_first() {
+        let selected =
+            BranchAndBound.select(candidates(&[4, 4, 4]), NativeCurrencyAmount::coins(6));
+        assert_eq!(leaf_indices(selected), vec![0, 1]);
+    }
+
+    #[test]
+    fn random_order_covers_target() {
+        let target = NativeCurrencyAmount::coins(10);
+        let selected = RandomOrder
+            .select(candidates(&[3, 3, 3, 3, 3]), target)
+            .expect("no selection");
+        assert_eq!(selected.len(), 4);
+    }
+
+    #[test]
+    fn shortfall_selects_nothing() {
+        let target = NativeCurrencyAmount::coins(20);
+        for selection in [
+            CoinSelection::FirstFit,
+            CoinSelection::LargestFirst,
+            CoinSelection::SmallestFirst,
+            CoinSelection::BranchAndBound,
+            CoinSelection::Random,
+        ] {
+            assert!(
+                selection
+                    .select(candidates(&[1, 5, 2, 8]), target)
+                    .is_none()
+            );
+        }
+    }
+
+    #[test]
+    fn strategies_parse_by_name() {
+        assert_eq!(CoinSelection::default(), CoinSelection::FirstFit);
+        assert_eq!(
+            " First-Fit ".parse::<CoinSelection>(),
+            Ok(CoinSelection::FirstFit)
+        );
+        assert_eq!(
+            "branch-and-bound".parse::<CoinSelection>(),
+            Ok(CoinSelection::BranchAndBound)
+        );
+        assert!("biggest".parse::<CoinSelection>().is_err());
+    }
+}